@@ -0,0 +1,102 @@
+//! Inputs shared between the benchmarks.
+#![allow(dead_code)]
+
+#[path = "../../tests/common/mod.rs"]
+mod generated;
+
+use std::io::Write;
+
+use flate2::write::GzEncoder;
+use flate2::Compression;
+
+use librezip::decompressed_block;
+use librezip::gzip;
+use librezip::parse_deflate;
+use librezip::Block;
+use librezip::CircularBuffer;
+use librezip::Code;
+
+use self::generated::words;
+use self::generated::xorshift;
+use self::generated::SEED;
+
+/// The test corpus files which are big enough to time.
+pub const CORPUS: &[(&str, &[u8])] = &[
+    (
+        "blockandabit",
+        include_bytes!("../../tests/data/blockandabit-sixteen-1.gz"),
+    ),
+    (
+        "blockandabitnewlines",
+        include_bytes!("../../tests/data/blockandabitnewlines-sixteen-1.gz"),
+    ),
+    (
+        "libcgi",
+        include_bytes!("../../tests/data/libcgi-untaint-email-perl_0.03.orig.tar.gz"),
+    ),
+    (
+        "librole",
+        include_bytes!("../../tests/data/librole-basic-perl_0.13-1.debian.tar.gz"),
+    ),
+];
+
+/// The corpus, then generated data, gzipped by zlib at its default level.
+pub fn inputs() -> Vec<(String, Vec<u8>)> {
+    let mut ret: Vec<(String, Vec<u8>)> = CORPUS
+        .iter()
+        .map(|&(name, gz)| (name.to_string(), gz.to_vec()))
+        .collect();
+
+    for (name, data) in [
+        ("words", words(256 * 1024)),
+        ("repeated-noise", repeated_noise(256 * 1024)),
+    ] {
+        let mut encoder = GzEncoder::new(Vec::new(), Compression::default());
+        encoder.write_all(&data).unwrap();
+        ret.push((name.to_string(), encoder.finish().unwrap()));
+    }
+
+    ret
+}
+
+/// The deflate stream inside a gzip file.
+pub fn deflate(gz: &[u8]) -> &[u8] {
+    let mut gz = gz;
+    gzip::discard_header(&mut gz).unwrap();
+    gz
+}
+
+/// Every block in a gzip file, its decompressed data, and all of their codes, in one list.
+pub fn decode(gz: &[u8]) -> (Vec<Block>, Vec<u8>, Vec<Code>) {
+    let blocks: Vec<Block> = parse_deflate(deflate(gz))
+        .map(|block| block.unwrap())
+        .collect();
+
+    let mut data = Vec::new();
+    let mut dictionary = CircularBuffer::new();
+    let mut codes = Vec::new();
+    for block in &blocks {
+        decompressed_block(&mut data, &mut dictionary, block).unwrap();
+        match *block {
+            Block::Uncompressed(_) => {}
+            Block::FixedHuffman(ref c) | Block::DynamicHuffman { codes: ref c, .. } => {
+                codes.extend(c)
+            }
+        }
+    }
+
+    (blocks, data, codes)
+}
+
+/// Lots of long matches, which gzip -1 doesn't insert into its dictionary, and little else.
+pub fn repeated_noise(len: usize) -> Vec<u8> {
+    let mut state = SEED;
+    let mut ret = Vec::with_capacity(len);
+    while ret.len() < len {
+        let chunk: Vec<u8> = (0..128).map(|_| xorshift(&mut state) as u8).collect();
+        ret.extend(&chunk);
+        ret.extend(&chunk);
+    }
+    ret.truncate(len);
+    ret
+}
@@ -0,0 +1,75 @@
+mod common;
+
+use std::io::Read;
+
+use criterion::criterion_group;
+use criterion::criterion_main;
+use criterion::BenchmarkId;
+use criterion::Criterion;
+use criterion::Throughput;
+use flate2::read::GzDecoder;
+
+use librezip::decompressed_block;
+use librezip::parse_deflate;
+use librezip::CircularBuffer;
+
+fn ours(gz: &[u8]) -> Vec<u8> {
+    let mut decompressed = Vec::new();
+    let mut dictionary = CircularBuffer::new();
+    for block in parse_deflate(common::deflate(gz)) {
+        decompressed_block(&mut decompressed, &mut dictionary, &block.unwrap()).unwrap();
+    }
+    decompressed
+}
+
+fn flate2(gz: &[u8]) -> Vec<u8> {
+    let mut decompressed = Vec::new();
+    GzDecoder::new(gz).read_to_end(&mut decompressed).unwrap();
+    decompressed
+}
+
+/// Parsing and decompressing, against `flate2`, which does the same job.
+fn decompress(c: &mut Criterion) {
+    let mut group = c.benchmark_group("decompress");
+
+    for (name, gz) in common::inputs() {
+        let expected = flate2(&gz);
+        assert_eq!(expected, ours(&gz), "{}", name);
+
+        group.throughput(Throughput::Bytes(expected.len() as u64));
+        group.bench_with_input(BenchmarkId::new("librezip", &name), &gz, |b, gz| {
+            b.iter(|| ours(gz))
+        });
+        group.bench_with_input(BenchmarkId::new("flate2", &name), &gz, |b, gz| {
+            b.iter(|| flate2(gz))
+        });
+    }
+
+    group.finish();
+}
+
+/// Only turning already-parsed codes back into data, which is all `CircularBuffer` does.
+fn decompress_codes(c: &mut Criterion) {
+    let mut group = c.benchmark_group("decompress_codes");
+
+    for (name, gz) in common::inputs() {
+        let (blocks, data, _) = common::decode(&gz);
+        group.throughput(Throughput::Bytes(data.len() as u64));
+
+        group.bench_with_input(BenchmarkId::from_parameter(name), &blocks, |b, blocks| {
+            b.iter(|| {
+                let mut decompressed = Vec::new();
+                let mut dictionary = CircularBuffer::new();
+                for block in blocks {
+                    decompressed_block(&mut decompressed, &mut dictionary, block).unwrap();
+                }
+                decompressed
+            })
+        });
+    }
+
+    group.finish();
+}
+
+criterion_group!(benches, decompress, decompress_codes);
+criterion_main!(benches);
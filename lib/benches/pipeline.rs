@@ -0,0 +1,99 @@
+//! Each stage of packing, and unpacking, over the corpus and generated data.
+//!
+//! Decompression is in `decompress`, against `flate2`.
+
+mod common;
+
+use std::io;
+
+use criterion::criterion_group;
+use criterion::criterion_main;
+use criterion::BenchmarkId;
+use criterion::Criterion;
+use criterion::Throughput;
+
+use librezip::all_refs::AllRefs;
+use librezip::parse_deflate;
+use librezip::serialise_trace;
+use librezip::technique::Emulator;
+use librezip::trace;
+use librezip::tracer::WINDOW_SIZE;
+use librezip::Config;
+
+fn parse(c: &mut Criterion) {
+    let mut group = c.benchmark_group("parse");
+
+    for (name, gz) in common::inputs() {
+        let (_, data, _) = common::decode(&gz);
+        group.throughput(Throughput::Bytes(data.len() as u64));
+        group.bench_with_input(BenchmarkId::from_parameter(name), &gz, |b, gz| {
+            b.iter(|| {
+                parse_deflate(common::deflate(gz))
+                    .collect::<Result<Vec<_>, _>>()
+                    .unwrap()
+            })
+        });
+    }
+
+    group.finish();
+}
+
+/// Setting up to trace, with a window of preroll, like every block after the first.
+fn with_sixteen(c: &mut Criterion) {
+    let mut group = c.benchmark_group("with_sixteen");
+
+    for (name, gz) in common::inputs() {
+        let (_, data, _) = common::decode(&gz);
+        let (preroll, data) = data.split_at(data.len().min(WINDOW_SIZE));
+        group.throughput(Throughput::Bytes((preroll.len() + data.len()) as u64));
+        group.bench_function(BenchmarkId::from_parameter(name), |b| {
+            b.iter(|| AllRefs::with_sixteen(preroll, data))
+        });
+    }
+
+    group.finish();
+}
+
+fn trace_and_restore(c: &mut Criterion) {
+    let mut group = c.benchmark_group("trace");
+    group.sample_size(10);
+
+    for (name, gz) in common::inputs() {
+        let (_, data, codes) = common::decode(&gz);
+        let technique = Emulator::new(Config::gzip(6), &[], &data);
+        let traced = trace::trace(&codes, &technique);
+
+        group.throughput(Throughput::Bytes(data.len() as u64));
+        group.bench_function(BenchmarkId::new("trace", &name), |b| {
+            b.iter(|| trace::trace(&codes, &technique))
+        });
+        group.bench_function(BenchmarkId::new("restore", &name), |b| {
+            b.iter(|| trace::restore(&traced, &technique).unwrap())
+        });
+    }
+
+    group.finish();
+}
+
+fn serialise(c: &mut Criterion) {
+    let mut group = c.benchmark_group("serialise_trace");
+
+    for (name, gz) in common::inputs() {
+        let (_, data, codes) = common::decode(&gz);
+        let traced = trace::trace(&codes, &Emulator::new(Config::gzip(6), &[], &data));
+        let serialised = serialise_trace::write(&traced);
+
+        group.throughput(Throughput::Elements(traced.len() as u64));
+        group.bench_function(BenchmarkId::new("write", &name), |b| {
+            b.iter(|| serialise_trace::write(&traced))
+        });
+        group.bench_function(BenchmarkId::new("read", &name), |b| {
+            b.iter(|| serialise_trace::read(io::Cursor::new(&serialised)).unwrap())
+        });
+    }
+
+    group.finish();
+}
+
+criterion_group!(benches, parse, with_sixteen, trace_and_restore, serialise);
+criterion_main!(benches);
@@ -0,0 +1,32 @@
+mod common;
+
+use criterion::criterion_group;
+use criterion::criterion_main;
+use criterion::BenchmarkId;
+use criterion::Criterion;
+use criterion::Throughput;
+
+use librezip::compress;
+use librezip::tracer;
+use librezip::Config;
+
+/// The time per byte should stay flat as the block grows.
+fn trace_gzip_1(c: &mut Criterion) {
+    let mut group = c.benchmark_group("trace_gzip_1");
+    group.sample_size(10);
+
+    for len in [16 * 1024, 64 * 1024, 256 * 1024, 1024 * 1024] {
+        let data = common::repeated_noise(len);
+        let codes = compress::codes(&data, Config::gzip(1));
+
+        group.throughput(Throughput::Bytes(len as u64));
+        group.bench_with_input(BenchmarkId::from_parameter(len), &data, |b, data| {
+            b.iter(|| tracer::trace_gzip(1, &[], data, &codes))
+        });
+    }
+
+    group.finish();
+}
+
+criterion_group!(benches, trace_gzip_1);
+criterion_main!(benches);
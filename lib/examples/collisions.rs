@@ -2,18 +2,35 @@ extern crate librezip;
 
 use std::collections::HashMap;
 use std::collections::HashSet;
-use std::u16;
+use std::env;
 
-use librezip::all_refs::Key;
+use librezip::chain_hash::ChainHash;
+
+/// `gzip` (the default), `zlib-{memLevel}`, `zlib-ng`, `libdeflate` or `crc32c-{bits}`.
+fn hash(name: &str) -> ChainHash {
+    if let Some(mem_level) = name.strip_prefix("zlib-").and_then(|n| n.parse().ok()) {
+        return ChainHash::zlib(mem_level);
+    }
+    if let Some(bits) = name.strip_prefix("crc32c-").and_then(|n| n.parse().ok()) {
+        return ChainHash::Crc32c { bits };
+    }
+    match name {
+        "gzip" => ChainHash::GZIP,
+        "zlib-ng" => ChainHash::ZLIB_NG,
+        "libdeflate" => ChainHash::LIBDEFLATE,
+        other => panic!("unrecognised hash: {:?}", other),
+    }
+}
 
 fn main() {
-    let mut map = HashMap::with_capacity(usize::from(u16::MAX));
+    let hash = hash(&env::args().nth(1).unwrap_or_else(|| "gzip".to_string()));
+    let mut map = HashMap::with_capacity(hash.table_size());
 
+    // printable three-byte keys; hashes of four bytes see them followed by a space
     for a in b' '..=b'~' {
         for b in b' '..=b'~' {
             for c in b' '..=b'~' {
-                let key = Key::from((a, b, c));
-                map.entry(key.sixteen_hash_16())
+                map.entry(hash.of(&[a, b, c, b' ']))
                     .or_insert_with(HashSet::new)
                     .insert((a, b, c));
             }
@@ -0,0 +1,34 @@
+#![no_main]
+#[macro_use] extern crate libfuzzer_sys;
+extern crate librezip;
+
+use std::io::Cursor;
+
+use librezip::CircularBuffer;
+
+fuzz_target!(|data: &[u8]| {
+    run(data);
+});
+
+/// Any input may be rejected, but nothing may panic.
+fn run(data: &[u8]) {
+    let mut reader = Cursor::new(data);
+    if librezip::gzip::discard_header(&mut reader).is_err() {
+        reader.set_position(0);
+    }
+
+    let mut decompressed = Vec::new();
+    let mut dictionary = CircularBuffer::new();
+
+    for block in librezip::parse_deflate(reader) {
+        let block = match block {
+            Ok(block) => block,
+            Err(_) => return,
+        };
+
+        // references can point before the start of the data
+        if librezip::decompressed_block(&mut decompressed, &mut dictionary, &block).is_err() {
+            return;
+        }
+    }
+}
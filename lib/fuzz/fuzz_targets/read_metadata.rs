@@ -0,0 +1,31 @@
+#![no_main]
+#[macro_use] extern crate libfuzzer_sys;
+extern crate librezip;
+
+use librezip::pack::Limits;
+use librezip::pack::Metadata;
+
+fuzz_target!(|data: &[u8]| {
+    run(data);
+});
+
+/// Metadata comes from untrusted uploads: it may be rejected, but nothing may panic, and it
+/// may not claim more than the limits.
+fn run(data: &[u8]) {
+    if data.len() < 2 {
+        return;
+    }
+
+    // the first two bytes say how much of the rest is metadata; the remainder is the data
+    let split = usize::from(u16::from_le_bytes([data[0], data[1]])).min(data.len() - 2);
+    let (metadata, decompressed) = data[2..].split_at(split);
+
+    let limits = Limits {
+        decompressed_len: 1 << 20,
+        raw_len: 1 << 20,
+    };
+
+    if let Ok(metadata) = Metadata::read_with_limits(metadata, limits) {
+        let _ = librezip::pack::unpack(&metadata, decompressed);
+    }
+}
@@ -0,0 +1,30 @@
+#![no_main]
+#[macro_use] extern crate libfuzzer_sys;
+extern crate librezip;
+
+use std::io::Cursor;
+
+use librezip::Block;
+
+fuzz_target!(|data: &[u8]| {
+    run(data);
+});
+
+/// Any stream we can parse must be written back out exactly as it was read.
+fn run(data: &[u8]) {
+    let mut it = librezip::parse_deflate(Cursor::new(data));
+
+    let mut blocks: Vec<Block> = Vec::new();
+    for block in &mut it {
+        match block {
+            Ok(block) => blocks.push(block),
+            Err(_) => return,
+        }
+    }
+
+    // the final padding has been read, and must have been empty, so this is whole bytes
+    let consumed = (it.consumed_bits() / 8) as usize;
+
+    let written = librezip::compressed_stream(Vec::new(), &blocks).expect("writing parsed blocks");
+    assert_eq!(&data[..consumed], &written[..], "blocks: {:?}", blocks);
+}
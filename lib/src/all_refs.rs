@@ -1,12 +1,17 @@
+use std::borrow::Cow;
 use std::convert::TryFrom;
 use std::fmt;
 use std::iter;
+use std::ops::Range;
 
 use crate::back_map::BackMap;
-use crate::obscure::obscure;
-use crate::Obscure;
+use crate::chain_hash::ChainHash;
 use crate::Ref;
 
+/// The furthest back gzip and zlib will look for a match: they keep `MIN_LOOKAHEAD` bytes of
+/// their window free for the data they're about to compress.
+pub const MAX_DIST: usize = 32_768 - (258 + 3 + 1);
+
 #[derive(Copy, Clone, Eq, PartialEq, Hash)]
 pub struct Key {
     b0: u8,
@@ -14,49 +19,61 @@ pub struct Key {
     b2: u8,
 }
 
-pub struct AllRefs<'p, 'd> {
-    pub preroll: &'p [u8],
-    pub data: &'d [u8],
-    map: BackMap,
-    limit: u16,
+pub struct AllRefs<'d> {
+    /// The preroll, then the data, so matches can be compared without caring which is which.
+    window: Cow<'d, [u8]>,
+    preroll_len: usize,
 }
 
-impl<'p, 'd> AllRefs<'p, 'd> {
-    pub fn with_sixteen(preroll: &'p [u8], data: &'d [u8], limit: u16) -> Self {
+impl<'d> AllRefs<'d> {
+    pub fn with_sixteen(preroll: &[u8], data: &'d [u8]) -> Self {
+        let window = if preroll.is_empty() {
+            Cow::Borrowed(data)
+        } else {
+            Cow::Owned([preroll, data].concat())
+        };
+
         AllRefs {
-            preroll,
-            data,
-            limit,
-            map: BackMap::from_window(preroll, data),
+            window,
+            preroll_len: preroll.len(),
         }
     }
 
+    pub fn preroll_len(&self) -> usize {
+        self.preroll_len
+    }
+
     pub fn data_len(&self) -> usize {
-        self.preroll.len() + self.data.len()
+        self.window.len()
     }
 
-    fn key(&self, data_pos: usize) -> Option<Key> {
-        if data_pos + 2 < self.data_len() {
-            Some(Key {
-                b0: self.get(data_pos),
-                b1: self.get(data_pos + 1),
-                b2: self.get(data_pos + 2),
-            })
-        } else {
-            None
-        }
+    pub fn key(&self, data_pos: usize) -> Option<Key> {
+        self.window
+            .get(data_pos..data_pos + 3)
+            .map(|bytes| Key::from((bytes[0], bytes[1], bytes[2])))
     }
 
+    /// The `hash` of the data at `pos`, if there's enough data left to compute it.
+    pub fn hash(&self, pos: usize, hash: ChainHash) -> Option<usize> {
+        self.window
+            .get(pos..pos + hash.key_len())
+            .map(|key| hash.of(key))
+    }
+
+    /// Every match for `pos` among the first `max_chain` positions on its hash chain, like
+    /// an encoder's search would find, nearest first. `pending` are positions the encoder has
+    /// inserted, but which aren't in the `chains` yet, as we're still looking ahead.
+    ///
     /// None if we are out of possible keys, or Some(possibly empty list)
     pub fn at<'m>(
         &'m self,
         pos: usize,
-        obscura: &'m [Obscure],
+        chains: &'m BackMap,
+        pending: Range<usize>,
+        max_chain: usize,
     ) -> Option<Box<dyn Iterator<Item = Ref> + 'm>> {
-        let key = match self.key(pos) {
-            Some(key) => key,
-            None => return None,
-        };
+        let key = self.key(pos)?;
+        let hash = self.hash(pos, chains.hash())?;
 
         // we can only find ourselves, which is invalid, and not handled by (inclusive) range code
         // Maybe I should fix the inclusive range code? Or pretend this is an optimisation.
@@ -64,63 +81,63 @@ impl<'p, 'd> AllRefs<'p, 'd> {
             return Some(Box::new(iter::empty()));
         }
 
+        let pending = pending
+            .rev()
+            .filter(move |&off| self.hash(off, chains.hash()) == Some(hash));
+
         Some(Box::new(
-            obscure(
-                self.map.get(key).filter(move |&off| off < pos),
-                obscura.iter().cloned(),
-            )
-            .take(usize::try_from(self.limit).expect("todo: usize"))
-            .filter(move |&off| pos - off <= 32_768)
-            .filter(move |&off| {
-                self.get(off) == key.b0
-                    && self.get(off + 1) == key.b1
-                    && self.get(off + 2) == key.b2
-            })
-            .map(move |off| {
-                let dist = u16::try_from(pos - off).expect("logically sound");
-                let run = self.possible_run_length_at(pos, dist);
-                Ref::new(dist, run)
-            }),
+            pending
+                .chain(chains.get(hash))
+                .enumerate()
+                // the head of the chain may be exactly `MAX_DIST` away, but the walk stops there
+                .take_while(move |&(i, off)| {
+                    pos - off < MAX_DIST || (0 == i && pos - off == MAX_DIST)
+                })
+                // every position on the chain counts, even if it turns out not to match
+                .take(max_chain)
+                .filter(move |&(_, off)| Some(key) == self.key(off))
+                .map(move |(_, off)| {
+                    let dist = u16::try_from(pos - off).expect("logically sound");
+                    let run = self.possible_run_length_at(pos, dist);
+                    Ref::new(dist, run)
+                }),
         ))
     }
 
     pub fn get(&self, pos: usize) -> u8 {
-        if pos < self.preroll.len() {
-            self.preroll[pos]
-        } else {
-            self.data[pos - self.preroll.len()]
-        }
-    }
-
-    fn get_at_dist(&self, data_pos: usize, dist: u16) -> u8 {
-        self.get(data_pos - usize::from(dist))
+        self.window[pos]
     }
 
+    /// How far the data at `pos` matches that `dist` before it, up to the longest reference.
     fn possible_run_length_at(&self, pos: usize, dist: u16) -> u16 {
-        let upcoming_data_len =
-            u16::try_from(258.min(self.data_len() - pos)).expect("logically sound");
-        let upcoming_data: Vec<u8> = (0..upcoming_data_len)
-            .map(|i| self.get(pos + usize::try_from(i).expect("todo: usize")))
-            .collect();
-
-        for cur in 3..dist.min(upcoming_data_len) {
-            if upcoming_data[usize::try_from(cur).expect("todo: usize")]
-                != self.get_at_dist(pos, dist - cur)
-            {
-                return cur;
-            }
-        }
+        let end = self.window.len().min(pos + 258);
+        let len = common_prefix(
+            &self.window[pos..end],
+            &self.window[pos - usize::from(dist)..end - usize::from(dist)],
+        );
+        u16::try_from(len).expect("at most 258")
+    }
+}
 
-        for cur in dist..upcoming_data_len {
-            if upcoming_data[usize::try_from(cur % dist).expect("todo: usize")]
-                != upcoming_data[usize::try_from(cur).expect("todo: usize")]
-            {
-                return cur;
-            }
+/// The number of bytes at the start of `a` which are the same in `b`, comparing a word at a time.
+fn common_prefix(a: &[u8], b: &[u8]) -> usize {
+    let mut len = 0;
+    for (a, b) in a.chunks_exact(8).zip(b.chunks_exact(8)) {
+        let a = u64::from_le_bytes(a.try_into().expect("exact"));
+        let b = u64::from_le_bytes(b.try_into().expect("exact"));
+        let diff = a ^ b;
+        if 0 != diff {
+            // the lowest set bit is in the first byte which differs, as the words are little-endian
+            return len + (diff.trailing_zeros() / 8) as usize;
         }
-
-        upcoming_data_len
+        len += 8;
     }
+
+    len + a[len..]
+        .iter()
+        .zip(&b[len..])
+        .take_while(|&(a, b)| a == b)
+        .count()
 }
 
 fn key_from_bytes(from: &[u8]) -> Key {
@@ -143,16 +160,7 @@ impl From<(u8, u8, u8)> for Key {
 
 impl Key {
     pub fn sixteen_hash_16(&self) -> u16 {
-        let mut hash = 0u16;
-
-        hash ^= u16::from(self.b0);
-        hash <<= 5;
-        hash ^= u16::from(self.b1);
-        hash <<= 5;
-        hash ^= u16::from(self.b2);
-
-        hash &= 0x7fff;
-        hash
+        u16::try_from(ChainHash::GZIP.of(&self.as_array())).expect("15 bits")
     }
 
     fn as_array(&self) -> [u8; 3] {
@@ -182,14 +190,21 @@ impl fmt::Debug for Key {
     }
 }
 
-impl<'p, 'd> fmt::Debug for AllRefs<'p, 'd> {
+impl<'d> fmt::Debug for AllRefs<'d> {
     fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
-        writeln!(f, "{:?}", self.map)
+        write!(
+            f,
+            "AllRefs({} + {} byte(s))",
+            self.preroll_len,
+            self.window.len() - self.preroll_len
+        )
     }
 }
 
 #[cfg(test)]
 mod tests {
+    use super::common_prefix;
+    use super::AllRefs;
     use super::Key;
 
     use crate::Code;
@@ -208,6 +223,34 @@ mod tests {
         );
     }
 
+    #[test]
+    fn prefixes() {
+        let data: Vec<u8> = (0..40).collect();
+        for len in 0..data.len() {
+            let mut other = data.clone();
+            other[len] = 0xff;
+            assert_eq!(len, common_prefix(&data, &other));
+        }
+        assert_eq!(data.len(), common_prefix(&data, &data));
+        assert_eq!(3, common_prefix(&data[..3], &data));
+    }
+
+    #[test]
+    fn runs() {
+        // across the preroll, and overlapping with itself
+        let refs = AllRefs::with_sixteen(b"xabc", b"abcabcabcd");
+        assert_eq!(9, refs.possible_run_length_at(4, 3));
+        assert_eq!(6, refs.possible_run_length_at(7, 3));
+        assert_eq!(3, refs.possible_run_length_at(10, 3));
+        assert_eq!(3, refs.possible_run_length_at(10, 9));
+
+        let long = vec![b'a'; 1000];
+        let refs = AllRefs::with_sixteen(&[], &long);
+        assert_eq!(258, refs.possible_run_length_at(1, 1));
+        assert_eq!(258, refs.possible_run_length_at(500, 300));
+        assert_eq!(9, refs.possible_run_length_at(991, 1));
+    }
+
     fn k(from: &[u8]) -> Key {
         assert_eq!(3, from.len());
         Key {
@@ -1,56 +1,54 @@
 use std::fmt;
 
-use itertools::Itertools;
+use crate::chain_hash::ChainHash;
 
-use crate::all_refs::Key;
-
-const HASH_SIZE: usize = 32 * 1024;
-
-/// This is an efficient way to compute and store a hashtable to an ordered list of positions.
+/// An encoder's hash chains: for each hash, the positions inserted with it, most recent first.
+///
+/// Positions are inserted one at a time, as the encoder would, so walking a chain visits the
+/// same positions it would, including ones whose keys merely share a hash.
+#[derive(Clone)]
 pub struct BackMap {
-    /// A lookup from the current `hash` to the last `pos` we saw that hash at.
-    hash_to_pos: [usize; HASH_SIZE],
+    hash: ChainHash,
 
-    /// A lookup from a `pos`, to the last `pos` where something had the same hash.
+    /// A lookup from a `hash` to the last `pos` inserted with it, plus one; zero if there's none.
+    hash_to_pos: Box<[usize]>,
+
+    /// A lookup from a `pos`, to the previous `pos` inserted with the same hash, plus one.
     pos_to_pos: Box<[usize]>,
 }
 
 impl BackMap {
-    pub fn from_window(preroll: &[u8], data: &[u8]) -> BackMap {
-        let mut table = BackMap {
-            hash_to_pos: [0; HASH_SIZE],
-            pos_to_pos: vec![0; preroll.len() + data.len()].into_boxed_slice(),
-        };
-
-        for (pos, keys) in preroll
-            .into_iter()
-            .chain(data.into_iter())
-            .cloned()
-            .tuple_windows::<(u8, u8, u8)>()
-            .enumerate()
-        {
-            let hash = Key::from(keys).sixteen_hash_16();
-            let hash_entry = &mut table.hash_to_pos[usize::from(hash)];
-            let prev_pos = *hash_entry;
-            table.pos_to_pos[pos] = prev_pos;
-            *hash_entry = pos;
+    /// Chains for each of the `hash`'s values, with room for positions up to `len`.
+    pub fn new(hash: ChainHash, len: usize) -> BackMap {
+        BackMap {
+            hash,
+            hash_to_pos: vec![0; hash.table_size()].into_boxed_slice(),
+            pos_to_pos: vec![0; len].into_boxed_slice(),
         }
+    }
 
-        table
+    pub fn hash(&self) -> ChainHash {
+        self.hash
     }
 
-    pub fn get(&self, key: Key) -> Chain {
-        let pos = self.hash_to_pos[usize::from(key.sixteen_hash_16())];
+    /// `hash` is the `hash` of the data at `pos`.
+    pub fn insert(&mut self, pos: usize, hash: usize) {
+        let hash_entry = &mut self.hash_to_pos[hash];
+        self.pos_to_pos[pos] = *hash_entry;
+        *hash_entry = pos + 1;
+    }
 
+    pub fn get(&self, hash: usize) -> Chain<'_> {
         Chain {
-            next: Some(pos),
+            next: self.hash_to_pos[hash],
             pos_to_pos: &self.pos_to_pos,
         }
     }
 }
 
 pub struct Chain<'a> {
-    next: Option<usize>,
+    /// Plus one, like the `BackMap`.
+    next: usize,
     pos_to_pos: &'a [usize],
 }
 
@@ -58,42 +56,68 @@ impl<'a> Iterator for Chain<'a> {
     type Item = usize;
 
     fn next(&mut self) -> Option<Self::Item> {
-        let current = match self.next {
-            Some(val) => val,
-            None => return None,
-        };
-
-        match self.pos_to_pos[current] {
-            0 => self.next = None,
-            next => self.next = Some(next),
-        };
-
+        let current = self.next.checked_sub(1)?;
+        self.next = self.pos_to_pos[current];
         Some(current)
     }
 }
 
 impl fmt::Debug for BackMap {
     fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
-        for (hash, &pos) in self
+        for (hash, &next) in self
             .hash_to_pos
             .iter()
             .enumerate()
-            .filter(|&(_, &pos)| 0 != pos)
+            .filter(|&(_, &next)| 0 != next)
         {
-            let mut vals = Vec::new();
-            let mut current = pos;
-            vals.push(current);
-            loop {
-                current = self.pos_to_pos[current];
-                if 0 == current {
-                    break;
-                }
-
-                vals.push(current);
+            let mut vals: Vec<usize> = Chain {
+                next,
+                pos_to_pos: &self.pos_to_pos,
             }
+            .collect();
             vals.reverse();
             writeln!(f, " - {:04x}: {:?}", hash, vals)?;
         }
         Ok(())
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::BackMap;
+    use crate::chain_hash::ChainHash;
+
+    #[test]
+    fn chains() {
+        let gzip = ChainHash::GZIP;
+        let mut map = BackMap::new(gzip, 10);
+        let abc = gzip.of(b"abc");
+        let xyz = gzip.of(b"xyz");
+        assert_eq!(0, map.get(abc).count());
+
+        map.insert(0, abc);
+        map.insert(3, xyz);
+        map.insert(5, abc);
+        assert_eq!(vec![5, 0], map.get(abc).collect::<Vec<_>>());
+        assert_eq!(vec![3], map.get(xyz).collect::<Vec<_>>());
+
+        // 'Ooo' and 'ooo' share a hash, so share a chain
+        let upper = gzip.of(b"Ooo");
+        let lower = gzip.of(b"ooo");
+        map.insert(7, upper);
+        map.insert(8, lower);
+        assert_eq!(vec![8, 7], map.get(upper).collect::<Vec<_>>());
+    }
+
+    #[test]
+    fn geometry() {
+        let zlib_ng = ChainHash::ZLIB_NG;
+        let mut map = BackMap::new(zlib_ng, 10);
+        assert_eq!(64 * 1024, map.hash_to_pos.len());
+
+        // a hash which mixes in every bit keeps them apart
+        map.insert(7, zlib_ng.of(b"Oooo"));
+        map.insert(8, zlib_ng.of(b"oooo"));
+        assert_eq!(vec![8], map.get(zlib_ng.of(b"oooo")).collect::<Vec<_>>());
+    }
+}
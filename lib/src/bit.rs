@@ -15,6 +15,7 @@ pub struct BitReader<R> {
     current: u8,
     remaining_bits: u8,
     track: Option<BitVec>,
+    consumed_bits: u64,
 }
 
 pub struct BitWriter<W> {
@@ -29,9 +30,15 @@ impl<R: Read> BitReader<R> {
             current: 0,
             remaining_bits: 0,
             track: None,
+            consumed_bits: 0,
         }
     }
 
+    /// The number of bits which have been read from the underlying reader so far.
+    pub fn consumed_bits(&self) -> u64 {
+        self.consumed_bits
+    }
+
     fn position(&self) -> u8 {
         assert!(self.remaining_bits <= 7);
         (8 - self.remaining_bits) % 8
@@ -46,6 +53,7 @@ impl<R: Read> BitReader<R> {
         }
 
         self.remaining_bits -= 1;
+        self.consumed_bits += 1;
 
         let bit = (self.current >> (7 - self.remaining_bits)) & 1;
         let bit = 1 == bit;
@@ -86,6 +94,7 @@ impl<R: Read> BitReader<R> {
         self.inner
             .read_exact(&mut buf)
             .with_context(|| format_err!("reading a length-prefixed {} bytes", len))?;
+        self.consumed_bits += u64::from(len) * 8;
 
         Ok(buf)
     }
@@ -96,6 +105,7 @@ impl<R: Read> BitReader<R> {
 
         let mut buf = [0u8; 2];
         self.inner.read_exact(&mut buf)?;
+        self.consumed_bits += 16;
 
         Ok((u16::from(buf[1]) << 8) | u16::from(buf[0]))
     }
@@ -162,9 +172,10 @@ impl<W: Write> BitWriter<W> {
     }
 
     pub fn write_aligned_u16(&mut self, val: u16) -> Result<(), Error> {
+        // little endian, like `read_aligned_u16`
         self.inner.write_all(&[
-            u8::try_from(val >> 8).expect("bitshift"),
             u8::try_from(val & 0xFF).expect("mask"),
+            u8::try_from(val >> 8).expect("bitshift"),
         ])?;
         Ok(())
     }
@@ -241,8 +252,7 @@ impl BitVec {
         Some(answer)
     }
 
-    #[allow(unused)]
-    fn bytes(&self) -> &Vec<u8> {
+    pub fn bytes(&self) -> &Vec<u8> {
         &self.bytes
     }
 
@@ -359,7 +369,7 @@ impl<R: Read> BitSource for BitReader<R> {
 
 impl<'a> BitSource for StackIterator<'a> {
     fn read_bit(&mut self) -> Result<bool, Error> {
-        Ok(self.next().expect("TODO: EOF"))
+        self.next().ok_or_else(|| format_err!("ran out of bits"))
     }
 }
 
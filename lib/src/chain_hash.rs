@@ -0,0 +1,139 @@
+use crc::Algorithm;
+use crc::Crc;
+use crc::CRC_32_ISCSI;
+
+/// The `crc32` instruction, as used by zlib-ng: CRC-32C, but starting from zero, and not inverted.
+const CRC32C_RAW: Crc<u32> = Crc::<u32>::new(&Algorithm {
+    init: 0,
+    xorout: 0,
+    ..CRC_32_ISCSI
+});
+
+/// How an encoder picks the hash chain for a position, from the bytes there.
+///
+/// Only positions whose hashes collide end up on the same chain, so this decides which matches
+/// an encoder can find, and how much of its chain limit is spent on ones that don't match.
+#[derive(Copy, Clone, Debug, Eq, PartialEq)]
+pub enum ChainHash {
+    /// zlib's rolling hash of three bytes, shifting each in by `(bits + 2) / 3`;
+    /// also used by gzip and miniz, with 15 bits.
+    Shift { bits: u8 },
+
+    /// The top `bits` of the first `bytes` (3 or 4) little-endian bytes, times a constant,
+    /// like libdeflate and zlib-ng.
+    Multiply {
+        bytes: u8,
+        multiplier: u32,
+        bits: u8,
+    },
+
+    /// The low `bits` of the `crc32` instruction over four bytes, like zlib-ng on x86.
+    Crc32c { bits: u8 },
+}
+
+impl ChainHash {
+    /// gzip, and zlib at its default `memLevel` of 8.
+    pub const GZIP: ChainHash = ChainHash::Shift { bits: 15 };
+
+    /// zlib-ng 2's four byte hash.
+    pub const ZLIB_NG: ChainHash = ChainHash::Multiply {
+        bytes: 4,
+        multiplier: 0x9e37_79b1,
+        bits: 16,
+    };
+
+    /// libdeflate's hash for three byte matches.
+    pub const LIBDEFLATE: ChainHash = ChainHash::Multiply {
+        bytes: 3,
+        multiplier: 0x1e35_a7bd,
+        bits: 15,
+    };
+
+    /// zlib with `memLevel` between 1 and 9, which uses `memLevel + 7` bits.
+    pub fn zlib(mem_level: u8) -> Self {
+        assert!(
+            (1..=9).contains(&mem_level),
+            "memLevel is between 1 and 9, inclusive"
+        );
+        ChainHash::Shift {
+            bits: mem_level + 7,
+        }
+    }
+
+    /// The number of bytes the hash reads; positions without this many left aren't inserted.
+    pub fn key_len(&self) -> usize {
+        match *self {
+            ChainHash::Shift { .. } => 3,
+            ChainHash::Multiply { bytes, .. } => usize::from(bytes),
+            ChainHash::Crc32c { .. } => 4,
+        }
+    }
+
+    /// The number of chains; every hash is below this.
+    pub fn table_size(&self) -> usize {
+        let bits = match *self {
+            ChainHash::Shift { bits } => bits,
+            ChainHash::Multiply { bits, .. } => bits,
+            ChainHash::Crc32c { bits } => bits,
+        };
+        1 << bits
+    }
+
+    /// The hash of the first `key_len` bytes of `key`.
+    pub fn of(&self, key: &[u8]) -> usize {
+        let key = &key[..self.key_len()];
+        let mask = self.table_size() - 1;
+        match *self {
+            ChainHash::Shift { bits } => {
+                let shift = bits.div_ceil(3);
+                key.iter()
+                    .fold(0, |hash, &b| ((hash << shift) ^ usize::from(b)) & mask)
+            }
+            ChainHash::Multiply {
+                multiplier, bits, ..
+            } => {
+                let value = key
+                    .iter()
+                    .rev()
+                    .fold(0u32, |value, &b| value << 8 | u32::from(b));
+                (value.wrapping_mul(multiplier) >> (32 - bits)) as usize
+            }
+            ChainHash::Crc32c { .. } => CRC32C_RAW.checksum(key) as usize & mask,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::ChainHash;
+
+    #[test]
+    fn zlib() {
+        assert_eq!(ChainHash::GZIP, ChainHash::zlib(8));
+        assert_eq!(0b0000_1100_0010_0001, ChainHash::GZIP.of(&[3, 1, 1]));
+        assert_eq!(0b0011_0000_0100_0001, ChainHash::zlib(9).of(&[3, 1, 1]));
+        assert_eq!(256, ChainHash::zlib(1).table_size());
+
+        // only the low bits of the first byte survive
+        for hash in [ChainHash::GZIP, ChainHash::zlib(9)] {
+            assert_eq!(hash.of(b"Ooo"), hash.of(b"ooo"));
+        }
+    }
+
+    #[test]
+    fn others() {
+        assert_eq!(0x630d, ChainHash::ZLIB_NG.of(b"abcd"));
+        assert_eq!(0x6d50, ChainHash::LIBDEFLATE.of(b"abcd"));
+        assert_eq!(0x41f6, ChainHash::Crc32c { bits: 15 }.of(b"abcd"));
+
+        // bytes past the key are ignored
+        assert_eq!(
+            ChainHash::LIBDEFLATE.of(b"abc?"),
+            ChainHash::LIBDEFLATE.of(b"abcd")
+        );
+        assert_ne!(
+            ChainHash::ZLIB_NG.of(b"Oooo"),
+            ChainHash::ZLIB_NG.of(b"oooo")
+        );
+    }
+}
@@ -1,3 +1,4 @@
+use std::convert::TryFrom;
 use std::io::Write;
 
 use anyhow::ensure;
@@ -35,17 +36,19 @@ impl CircularBuffer {
     }
 
     pub fn extend(&mut self, val: &[u8]) {
-        // TODO: optimise
+        let cap = self.data.len();
 
-        for byte in val {
-            self.push(*byte);
-        }
+        // only the end could possibly be kept
+        let val = &val[val.len().saturating_sub(cap)..];
+
+        let first = val.len().min(cap - self.idx);
+        self.data[self.idx..self.idx + first].copy_from_slice(&val[..first]);
+        self.data[..val.len() - first].copy_from_slice(&val[first..]);
+        self.advance(val.len());
     }
 
     // This updates self, whereas run_from and friends do not.
     pub fn copy<W: Write>(&mut self, dist: u16, len: u16, mut into: W) -> Result<(), Error> {
-        // TODO: optimise
-
         ensure!(
             dist > 0 && dist <= self.valid_cap,
             "dist must fit: {} / {}",
@@ -53,22 +56,61 @@ impl CircularBuffer {
             self.valid_cap
         );
 
-        let mut read_from = (self
-            .idx
-            .wrapping_sub(usize::from(dist))
-            .wrapping_add(self.data.len()))
-            % self.data.len();
-
-        for _ in 0..len {
-            let b = self.data[read_from];
-            read_from = (read_from + 1) % self.data.len();
-            into.write_all(&[b])?;
-            self.push(b);
+        let cap = self.data.len();
+        let dist = usize::from(dist);
+        let mut remaining = usize::from(len);
+
+        while remaining > 0 {
+            // the longest reference; longer copies are the same as several, one after another
+            let len = remaining.min(258);
+            let start = (self.idx + cap - dist) % cap;
+
+            if dist >= len && start + len <= cap && self.idx + len <= cap {
+                // the source and destination don't overlap, or wrap, so copy straight across
+                self.data.copy_within(start..start + len, self.idx);
+                into.write_all(&self.data[self.idx..self.idx + len])?;
+                self.advance(len);
+            } else {
+                let mut buf = [0u8; 258];
+                self.copy_into(dist, &mut buf[..len]);
+                into.write_all(&buf[..len])?;
+                self.extend(&buf[..len]);
+            }
+
+            remaining -= len;
         }
 
         Ok(())
     }
 
+    /// `len` bytes have been written at `idx`.
+    fn advance(&mut self, len: usize) {
+        self.idx = (self.idx + len) % self.data.len();
+        self.valid_cap = u16::try_from(self.data.len().min(usize::from(self.valid_cap) + len))
+            .expect("capacity is a u16");
+    }
+
+    /// Fill `out` with what a reference to `dist` would produce, without updating self.
+    fn copy_into(&self, dist: usize, out: &mut [u8]) {
+        let cap = self.data.len();
+        let start = (self.idx + cap - dist) % cap;
+
+        // the bytes already in the buffer; the source might wrap around the end
+        let available = dist.min(out.len());
+        let first = available.min(cap - start);
+        out[..first].copy_from_slice(&self.data[start..start + first]);
+        out[first..available].copy_from_slice(&self.data[..available - first]);
+
+        // an overlapping reference repeats them; double what we have, keeping a whole number
+        // of repeats, until we're done
+        let mut filled = available;
+        while filled < out.len() {
+            let len = filled.min(out.len() - filled);
+            out.copy_within(..len, filled);
+            filled += len;
+        }
+    }
+
     #[inline]
     pub fn get_at_dist(&self, dist: u16) -> u8 {
         debug_assert!(
@@ -97,13 +139,8 @@ impl CircularBuffer {
     }
 
     pub fn vec(&self) -> Vec<u8> {
-        // TODO: optimise
-
-        let mut ret = Vec::with_capacity(usize::from(self.valid_cap));
-        for pos in (1..1 + self.valid_cap).rev() {
-            ret.push(self.get_at_dist(pos));
-        }
-
+        let mut ret = vec![0; usize::from(self.valid_cap)];
+        self.copy_into(ret.len(), &mut ret);
         ret
     }
 }
@@ -134,6 +171,57 @@ mod tests {
         buf.get_at_dist(7);
     }
 
+    #[test]
+    fn copies() {
+        let mut buf = CircularBuffer::with_capacity(10);
+        buf.extend(b"abcdefgh");
+        let mut out = Vec::new();
+
+        // wrapping around the end, both while reading and writing
+        buf.copy(5, 4, &mut out).unwrap();
+        assert_eq!(b"defg", out.as_slice());
+        assert_eq!(b"cdefghdefg", buf.vec().as_slice());
+
+        // overlapping
+        out.clear();
+        buf.copy(3, 8, &mut out).unwrap();
+        assert_eq!(b"efgefgef", out.as_slice());
+        assert_eq!(b"fgefgefgef", buf.vec().as_slice());
+
+        // longer than any reference, and than the buffer
+        out.clear();
+        buf.copy(2, 300, &mut out).unwrap();
+        assert_eq!(b"ef".repeat(150), out);
+        assert_eq!(b"efefefefef", buf.vec().as_slice());
+
+        assert!(buf.copy(11, 1, &mut out).is_err());
+    }
+
+    #[test]
+    fn matches_byte_at_a_time() {
+        let mut buf = CircularBuffer::with_capacity(100);
+        let mut expected = Vec::new();
+        let mut out = Vec::new();
+        for i in 0..1000usize {
+            if i % 3 == 0 || expected.len() < 10 {
+                let fresh = vec![i as u8; i % 7];
+                buf.extend(&fresh);
+                expected.extend(fresh);
+                continue;
+            }
+
+            let dist = 1 + i * 7 % expected.len().min(100);
+            let len = 3 + i % 250;
+            for _ in 0..len {
+                expected.push(expected[expected.len() - dist]);
+            }
+            out.clear();
+            buf.copy(dist as u16, len as u16, &mut out).unwrap();
+            assert_eq!(&expected[expected.len() - len..], out.as_slice());
+        }
+        assert_eq!(&expected[expected.len() - 100..], buf.vec().as_slice());
+    }
+
     #[test]
     fn veccy() {
         let mut buf = CircularBuffer::with_capacity(6);
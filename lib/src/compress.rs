@@ -0,0 +1,203 @@
+use crc::Crc;
+use crc::CRC_32_ISO_HDLC;
+
+use crate::cost;
+use crate::serialise::compressed_stream;
+use crate::technique::Config;
+use crate::technique::Emulator;
+use crate::tracer;
+use crate::Block;
+use crate::Code;
+
+const CRC32: Crc<u32> = Crc::<u32>::new(&CRC_32_ISO_HDLC);
+
+/// zlib's default `lit_bufsize`; we don't attempt its early flushing heuristics.
+const MAX_CODES_PER_BLOCK: usize = 0x4000 - 1;
+
+/// Compress `data` into a raw `DEFLATE` stream, making the choices `config` would.
+pub fn compress(data: &[u8], config: Config) -> Vec<u8> {
+    compressed_stream(Vec::new(), &blocks(data, config)).expect("writing to memory")
+}
+
+/// `compress`, with a minimal gzip header and trailer.
+pub fn gzip(data: &[u8], config: Config) -> Vec<u8> {
+    // no flags, no mtime, no extra flags, os: unix
+    let mut ret = vec![0x1f, 0x8b, 0x08, 0, 0, 0, 0, 0, 0, 3];
+    ret.extend(compress(data, config));
+    ret.extend(&CRC32.checksum(data).to_le_bytes());
+    ret.extend(&(data.len() as u32).to_le_bytes());
+    ret
+}
+
+/// `compress`, with zlib's default header, and trailer.
+pub fn zlib(data: &[u8], config: Config) -> Vec<u8> {
+    let mut ret = vec![0x78, 0x9c];
+    ret.extend(compress(data, config));
+    ret.extend(&adler32(data).to_be_bytes());
+    ret
+}
+
+/// Every code `config` would pick for `data`, if it was the whole stream.
+pub fn codes(data: &[u8], config: Config) -> Vec<Code> {
+    tracer::guess(&Emulator::new(config, &[], data))
+}
+
+/// The `codes`, split into blocks, each stored, fixed or dynamic huffman, whichever is smallest.
+pub fn blocks(data: &[u8], config: Config) -> Vec<Block> {
+    let fixed = cost::fixed_lengths();
+
+    let mut ret = Vec::new();
+    let mut pos = 0;
+
+    for chunk in codes(data, config).chunks(MAX_CODES_PER_BLOCK) {
+        let len: usize = chunk.iter().map(|c| usize::from(c.emitted_bytes())).sum();
+        let raw = &data[pos..pos + len];
+        pos += len;
+
+        let (trees, dynamic) = cost::dynamic_trees(chunk);
+        let stored_bits = cost::stored_bits(len);
+        let fixed_bits = cost::huffman_bits(&fixed, chunk).expect("fixed trees are complete");
+        let dynamic_bits =
+            trees.len() as u64 + cost::huffman_bits(&dynamic, chunk).expect("built for them");
+
+        if stored_bits < fixed_bits.min(dynamic_bits) {
+            ret.extend(
+                raw.chunks(cost::MAX_STORED_LEN)
+                    .map(|part| Block::Uncompressed(part.to_vec())),
+            );
+        } else if dynamic_bits < fixed_bits {
+            ret.push(Block::DynamicHuffman {
+                trees,
+                codes: chunk.to_vec(),
+            });
+        } else {
+            ret.push(Block::FixedHuffman(chunk.to_vec()));
+        }
+    }
+
+    if ret.is_empty() {
+        // a stream must contain at least one (final) block
+        ret.push(Block::FixedHuffman(Vec::new()));
+    }
+
+    ret
+}
+
+fn adler32(data: &[u8]) -> u32 {
+    const MOD: u32 = 65521;
+
+    let mut a = 1u32;
+    let mut b = 0u32;
+
+    for &byte in data {
+        a = (a + u32::from(byte)) % MOD;
+        b = (b + a) % MOD;
+    }
+
+    (b << 16) | a
+}
+
+#[cfg(test)]
+mod tests {
+    use std::io::Read;
+
+    use flate2::read::DeflateDecoder;
+    use flate2::read::GzDecoder;
+    use flate2::read::ZlibDecoder;
+
+    use super::*;
+    use crate::parse;
+    use crate::test_data;
+    use crate::Trace;
+
+    fn libcgi() -> Vec<u8> {
+        let mut data = Vec::new();
+        GzDecoder::new(
+            &include_bytes!("../tests/data/libcgi-untaint-email-perl_0.03.orig.tar.gz")[..],
+        )
+        .read_to_end(&mut data)
+        .unwrap();
+        data
+    }
+
+    fn inflate<R: Read>(mut decoder: R) -> Vec<u8> {
+        let mut ret = Vec::new();
+        decoder.read_to_end(&mut ret).unwrap();
+        ret
+    }
+
+    #[test]
+    fn round_trip() {
+        let libcgi = libcgi();
+        for data in &[
+            &b""[..],
+            b"a",
+            b"abcdef bcdefg",
+            b"aaaaaaaaaaaaaaaa",
+            &libcgi,
+        ] {
+            for level in &[1, 6, 9] {
+                let compressed = compress(data, Config::gzip(*level));
+                assert_eq!(
+                    *data,
+                    inflate(DeflateDecoder::new(&compressed[..])).as_slice()
+                );
+            }
+        }
+    }
+
+    #[test]
+    fn containers() {
+        let data = libcgi();
+        let gz = gzip(&data, Config::gzip(6));
+        assert_eq!(data, inflate(GzDecoder::new(&gz[..])));
+        let zlib = zlib(&data, Config::gzip(6));
+        assert_eq!(data, inflate(ZlibDecoder::new(&zlib[..])));
+    }
+
+    #[test]
+    fn like_gzip() {
+        let orig = include_bytes!("../tests/data/libcgi-untaint-email-perl_0.03.orig.tar.gz");
+        let data = libcgi();
+        let blocks = blocks(&data, Config::gzip(6));
+        assert!(blocks
+            .iter()
+            .any(|block| matches!(*block, Block::DynamicHuffman { .. })));
+
+        let gz = gzip(&data, Config::gzip(6));
+        assert!(gz.len() < orig.len() * 11 / 10);
+    }
+
+    #[test]
+    fn incompressible() {
+        let data: Vec<u8> = test_data::noise().take(100_000).map(|n| n as u8).collect();
+
+        let blocks = blocks(&data, Config::gzip(1));
+        assert!(blocks
+            .iter()
+            .all(|block| matches!(*block, Block::Uncompressed(_))));
+
+        let compressed = compress(&data, Config::gzip(1));
+        // a byte of header, and four of lengths, per block
+        assert!(compressed.len() <= data.len() + 5 * blocks.len());
+        assert_eq!(data, inflate(DeflateDecoder::new(&compressed[..])));
+    }
+
+    #[test]
+    fn traces_perfectly() {
+        // the compressor and the tracer are the same model, so it should predict itself exactly
+        let data = b"a3456789a23456a123412f4123456789";
+        for level in 1..=9 {
+            let compressed = compress(data, Config::gzip(level));
+            let mut blocks = parse::parse_deflate(&compressed[..]);
+            let codes = match blocks.next().unwrap().unwrap() {
+                Block::FixedHuffman(codes) | Block::DynamicHuffman { codes, .. } => codes,
+                other => panic!("unexpected block: {:?}", other),
+            };
+            assert!(blocks.next().is_none());
+
+            let trace = tracer::trace_gzip(level, &[], data, &codes);
+            assert!(trace.iter().all(|t| Trace::Correct == *t), "{:?}", trace);
+        }
+    }
+}
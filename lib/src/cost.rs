@@ -0,0 +1,252 @@
+use anyhow::Error;
+
+use crate::bit::BitVec;
+use crate::code_tree::CodeTree;
+use crate::huffman;
+use crate::serialise::Lengths;
+use crate::Block;
+use crate::Code;
+use crate::Trace;
+use crate::Tracer;
+
+pub const MAX_STORED_LEN: usize = 0xffff;
+
+/// What a block cost, in bits, and what it could have cost.
+#[derive(Debug)]
+pub struct BlockCost {
+    /// The decompressed length of the block.
+    pub len: usize,
+
+    /// The block as it was encoded, including its header and any trees.
+    pub actual: u64,
+
+    /// Our predicted codes, under the block's own trees. None for stored blocks, or if
+    /// we predicted a symbol the trees can't represent.
+    pub predicted_same_trees: Option<u64>,
+
+    /// Our predicted codes, in a dynamic block with trees built for them.
+    pub predicted: u64,
+
+    /// The encoder's codes, in a fixed huffman block. None for stored blocks.
+    pub fixed: Option<u64>,
+
+    /// The data, as a sequence of stored blocks.
+    pub stored: u64,
+
+    /// Every point at which the encoder didn't do what we predicted.
+    pub decisions: Vec<Decision>,
+}
+
+/// A point at which the encoder's choice differed from our guess.
+#[derive(Debug)]
+pub struct Decision {
+    /// The position in the block's decompressed data.
+    pub pos: usize,
+    pub actual: Code,
+    pub guess: Code,
+    /// The cost of `actual` under the block's trees, if it's a huffman block.
+    pub actual_bits: Option<u8>,
+    /// The cost of `guess` under the block's trees, if they can represent it.
+    pub guess_bits: Option<u8>,
+}
+
+impl Decision {
+    /// Some(true) if the encoder's choice was cheaper per byte than our guess, under its trees.
+    pub fn cheaper(&self) -> Option<bool> {
+        let actual = u64::from(self.actual_bits?) * u64::from(self.guess.emitted_bytes());
+        let guess = u64::from(self.guess_bits?) * u64::from(self.actual.emitted_bytes());
+        Some(actual < guess)
+    }
+}
+
+/// Price `block`, whose decompressed data is `data`, against what the `tracer` would have done,
+/// then move the `tracer` past it, like tracing it would.
+///
+/// `following` is the data after the block, as for `Tracer::trace_block`.
+pub fn block_cost(
+    tracer: &mut Tracer,
+    data: &[u8],
+    following: &[u8],
+    block: &Block,
+) -> Result<BlockCost, Error> {
+    let predicted_codes = tracer.guess_block(data, following);
+    let (trees, predicted_lengths) = dynamic_trees(&predicted_codes);
+    let predicted = trees.len() as u64
+        + huffman_bits(&predicted_lengths, &predicted_codes).expect("built for them");
+
+    let stored = stored_bits(data.len());
+
+    let (lengths, tree_bits, codes) = match *block {
+        Block::Uncompressed(_) => {
+            tracer.skip_block(data, following);
+            return Ok(BlockCost {
+                len: data.len(),
+                actual: stored,
+                predicted_same_trees: None,
+                predicted,
+                fixed: None,
+                stored,
+                decisions: Vec::new(),
+            });
+        }
+        Block::FixedHuffman(ref codes) => (fixed_lengths(), 0, codes),
+        Block::DynamicHuffman {
+            ref trees,
+            ref codes,
+        } => {
+            let (length, distance) = huffman::read_codes(&mut trees.iter())?;
+            (
+                Lengths::new(&length, distance.as_ref()),
+                trees.len() as u64,
+                codes,
+            )
+        }
+    };
+
+    let actual = tree_bits
+        + huffman_bits(&lengths, codes)
+            .ok_or_else(|| anyhow::anyhow!("block contains codes its trees can't represent"))?;
+
+    Ok(BlockCost {
+        len: data.len(),
+        actual,
+        predicted_same_trees: huffman_bits(&lengths, &predicted_codes).map(|bits| tree_bits + bits),
+        predicted,
+        fixed: huffman_bits(&fixed_lengths(), codes),
+        stored,
+        decisions: decisions(tracer, data, following, codes, &lengths),
+    })
+}
+
+fn decisions(
+    tracer: &mut Tracer,
+    data: &[u8],
+    following: &[u8],
+    codes: &[Code],
+    lengths: &Lengths,
+) -> Vec<Decision> {
+    let mut guesses = Vec::new();
+    let trace = tracer.trace_block_with(data, following, codes, |remaining, _| {
+        guesses.push(remaining[0])
+    });
+
+    let mut guesses = guesses.into_iter();
+    let mut pos = 0;
+    let mut ret = Vec::new();
+
+    for (t, &actual) in trace.iter().zip(codes) {
+        if Trace::Correct != *t {
+            let guess = guesses.next().expect("one per miss");
+            ret.push(Decision {
+                pos,
+                actual,
+                guess,
+                actual_bits: lengths.length(&actual),
+                guess_bits: lengths.length(&guess),
+            });
+        }
+        pos += usize::from(actual.emitted_bytes());
+    }
+
+    ret
+}
+
+pub fn fixed_lengths() -> Lengths {
+    Lengths::new(
+        &huffman::FIXED_LENGTH_TREE,
+        Some(&huffman::FIXED_DISTANCE_TREE),
+    )
+}
+
+/// The serialised trees for the `codes`, built like zlib does, and the resulting code lengths.
+pub fn dynamic_trees(codes: &[Code]) -> (BitVec, Lengths) {
+    let (length_freqs, distance_freqs) = huffman::frequencies(codes);
+    let length = huffman::zlib_lengths(&length_freqs, huffman::MAX_CODE_LEN);
+    let distance = huffman::zlib_lengths(&distance_freqs, huffman::MAX_CODE_LEN);
+
+    let lengths = Lengths::new(
+        &CodeTree::new(&length).expect("built a complete tree"),
+        Some(&CodeTree::new(&distance).expect("built a complete tree")),
+    );
+
+    (huffman::write_trees(&length, &distance), lengths)
+}
+
+/// Including the block type and end of block marker, but not any trees.
+///
+/// None if the trees can't represent one of the `codes`.
+pub fn huffman_bits(lengths: &Lengths, codes: &[Code]) -> Option<u64> {
+    let mut bits = 3 + u64::from(lengths.end_of_block());
+
+    for code in codes {
+        bits += u64::from(lengths.length(code)?);
+    }
+
+    Some(bits)
+}
+
+/// Including the block headers, assuming the worst alignment for each.
+pub fn stored_bits(len: usize) -> u64 {
+    let blocks = len.div_ceil(MAX_STORED_LEN).max(1) as u64;
+    blocks * (3 + 7 + 32) + len as u64 * 8
+}
+
+#[cfg(test)]
+mod tests {
+    use std::io::Cursor;
+
+    use super::*;
+    use crate::circles::CircularBuffer;
+    use crate::gzip;
+    use crate::parse;
+    use crate::serialise::decompressed_block;
+    use crate::technique;
+
+    #[test]
+    fn libcgi() {
+        let orig = &include_bytes!("../tests/data/libcgi-untaint-email-perl_0.03.orig.tar.gz")[..];
+        let mut cursor = Cursor::new(orig);
+        let header = gzip::discard_header(&mut cursor).unwrap();
+
+        let mut dictionary = CircularBuffer::new();
+        let mut all = Vec::new();
+        let mut blocks = Vec::new();
+        for block in parse::parse_deflate(&mut cursor) {
+            let block = block.unwrap();
+            let start = all.len();
+            decompressed_block(&mut all, &mut dictionary, &block).unwrap();
+            blocks.push((block, start..all.len()));
+        }
+
+        let mut tracer = Tracer::new(technique::lookup("gzip-9").unwrap());
+        let mut total = 0;
+        let mut decisions = 0;
+
+        for (block, range) in &blocks {
+            let data = &all[range.clone()];
+            let cost = block_cost(&mut tracer, data, &all[range.end..], block).unwrap();
+            assert_eq!(data.len(), cost.len);
+            assert!(cost.actual < cost.stored);
+            if let Some(fixed) = cost.fixed {
+                assert!(cost.actual <= fixed);
+            }
+
+            assert!(cost.predicted_same_trees.is_some());
+            for decision in &cost.decisions {
+                assert_ne!(decision.actual, decision.guess);
+                assert!(decision.pos < data.len());
+                assert!(decision.cheaper().is_some());
+            }
+            decisions += cost.decisions.len();
+
+            total += cost.actual;
+        }
+
+        // every block is huffman, so `actual` is exact
+        let deflate_len = orig.len() - header.len() - 8;
+        assert_eq!(deflate_len as u64, total.div_ceil(8));
+
+        // gzip's lazy matching, which we don't predict perfectly
+        assert_ne!(0, decisions);
+    }
+}
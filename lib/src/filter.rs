@@ -1,3 +1,4 @@
+use std::io;
 use std::io::Read;
 use std::io::Result;
 use std::io::Write;
@@ -5,6 +6,16 @@ use std::io::Write;
 use sha2;
 use sha2::Digest;
 
+/// The length of every hash here, in bytes.
+pub const HASH_LEN: usize = 64;
+
+/// SHA-512 of some data we already have in memory.
+pub fn sha512(data: &[u8]) -> Vec<u8> {
+    let mut reader = FilterRead::new(data);
+    io::copy(&mut reader, &mut io::sink()).expect("reading from memory");
+    reader.hash()
+}
+
 pub struct FilterRead<R: Read> {
     inner: R,
     hash: sha2::Sha512,
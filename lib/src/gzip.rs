@@ -3,8 +3,30 @@ use std::io::Read;
 use anyhow::ensure;
 use anyhow::Error;
 
-pub fn discard_header<R: Read>(mut from: R) -> Result<Vec<u8>, Error> {
+/// The fields of a gzip member header.
+#[derive(Clone, Debug, Default, Eq, PartialEq)]
+pub struct Header {
+    pub text: bool,
+    pub mtime: u32,
+    /// Often (but not reliably) an indication of the compression level used.
+    pub extra_flags: u8,
+    pub os: u8,
+    pub extra: Option<Vec<u8>>,
+    /// Without the null terminator.
+    pub name: Option<Vec<u8>>,
+    /// Without the null terminator.
+    pub comment: Option<Vec<u8>>,
+    pub header_crc: Option<u16>,
+}
+
+pub fn discard_header<R: Read>(from: R) -> Result<Vec<u8>, Error> {
+    read_header(from).map(|(_, whole_thing)| whole_thing)
+}
+
+/// Returns the parsed header, and the exact bytes it occupied.
+pub fn read_header<R: Read>(mut from: R) -> Result<(Header, Vec<u8>), Error> {
     let mut whole_thing = Vec::new();
+    let mut parsed = Header::default();
 
     let mut header = [0u8; 10];
     from.read_exact(&mut header)?;
@@ -15,9 +37,13 @@ pub fn discard_header<R: Read>(mut from: R) -> Result<Vec<u8>, Error> {
 
     let flags = header[3];
     ensure!(0 == (flags & 0b1110_0000), "reserved flags bits set");
+    parsed.text = has_bit(flags, 0);
     // 4, 5, 6, 7: mtime
+    parsed.mtime = u32::from_le_bytes([header[4], header[5], header[6], header[7]]);
     // 8: extra flags (compression level)
+    parsed.extra_flags = header[8];
     // 9: OS
+    parsed.os = header[9];
 
     if has_bit(flags, 2) {
         // extra
@@ -28,16 +54,17 @@ pub fn discard_header<R: Read>(mut from: R) -> Result<Vec<u8>, Error> {
         let mut extra_field = vec![0u8; extra_field_length];
         from.read_exact(&mut extra_field)?;
         whole_thing.extend(&extra_field);
+        parsed.extra = Some(extra_field);
     }
 
     if has_bit(flags, 3) {
         // fname
-        read_null_terminated(&mut from, &mut whole_thing)?;
+        parsed.name = Some(read_null_terminated(&mut from, &mut whole_thing)?);
     }
 
     if has_bit(flags, 4) {
         // comment
-        read_null_terminated(&mut from, &mut whole_thing)?;
+        parsed.comment = Some(read_null_terminated(&mut from, &mut whole_thing)?);
     }
 
     if has_bit(flags, 1) {
@@ -45,9 +72,10 @@ pub fn discard_header<R: Read>(mut from: R) -> Result<Vec<u8>, Error> {
         let mut buf = [0u8; 2];
         from.read_exact(&mut buf)?;
         whole_thing.extend(&buf);
+        parsed.header_crc = Some(u16::from_le_bytes(buf));
     }
 
-    Ok(whole_thing)
+    Ok((parsed, whole_thing))
 }
 
 #[inline]
@@ -55,13 +83,38 @@ fn has_bit(val: u8, bit: u8) -> bool {
     (val & (1 << bit)) == (1 << bit)
 }
 
-fn read_null_terminated<R: Read>(mut from: R, into: &mut Vec<u8>) -> Result<(), Error> {
+/// Returns the string, without the terminator; `into` gets the terminator too.
+fn read_null_terminated<R: Read>(mut from: R, into: &mut Vec<u8>) -> Result<Vec<u8>, Error> {
+    let mut value = Vec::new();
     loop {
         let mut buf = [0u8; 1];
         from.read_exact(&mut buf)?;
         into.push(buf[0]);
         if 0 == buf[0] {
-            return Ok(());
+            return Ok(value);
         }
+        value.push(buf[0]);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn named() {
+        let orig = &include_bytes!("../tests/data/libcgi-untaint-email-perl_0.03.orig.tar.gz")[..];
+        let (header, raw) = read_header(orig).unwrap();
+        assert_eq!(orig[..raw.len()], raw[..]);
+        assert_eq!(
+            Header {
+                mtime: 0x3bdd_423a,
+                extra_flags: 2,
+                os: 3,
+                name: Some(b"CGI-Untaint-email-0.03.tar".to_vec()),
+                ..Header::default()
+            },
+            header
+        );
     }
 }
@@ -9,7 +9,20 @@ use lazy_static::lazy_static;
 
 use crate::bit::BitReader;
 use crate::bit::BitSource;
+use crate::bit::BitVec;
 use crate::code_tree::CodeTree;
+use crate::Code;
+
+/// The longest code allowed in the literal/length and distance trees.
+pub const MAX_CODE_LEN: u8 = 15;
+
+/// The longest code allowed in the tree which encodes the other trees.
+const MAX_CODE_LEN_CODE_LEN: u8 = 7;
+
+/// The order in which the code length code lengths are stored.
+const CODE_LEN_ORDER: [usize; 19] = [
+    16, 17, 18, 0, 8, 7, 9, 6, 10, 5, 11, 4, 12, 3, 13, 2, 14, 1, 15,
+];
 
 lazy_static! {
     pub static ref FIXED_LENGTH_TREE: CodeTree = {
@@ -180,7 +193,11 @@ pub fn decode_run_length<R: Read>(reader: &mut BitReader<R>, sym: u16) -> Result
 
         let high_part = (((sym - 265) as u8) % 4 + 4) << extra_bits;
         let low_part = reader.read_part(extra_bits)? as u8;
-        return Ok(u16::from(high_part) + u16::from(low_part) + 3);
+        let run = u16::from(high_part) + u16::from(low_part) + 3;
+
+        // 258 has its own symbol; this spelling of it can't be written back out
+        ensure!(run < 258, "non-canonical run length: {} via {}", run, sym);
+        return Ok(run);
     }
 
     if sym == 285 {
@@ -230,7 +247,511 @@ pub fn decode_distance<R: Read>(reader: &mut BitReader<R>, sym: u16) -> Result<u
     }
 }
 
+/// How often each literal/length symbol, including one end of block, and each distance symbol, is used.
+pub fn frequencies(codes: &[Code]) -> (Vec<u32>, Vec<u32>) {
+    let mut length = vec![0u32; 286];
+    let mut distance = vec![0u32; 30];
+
+    length[256] = 1;
+
+    for code in codes {
+        match *code {
+            Code::Literal(byte) => length[usize::from(byte)] += 1,
+            Code::Reference(r) => {
+                length[usize::from(encode_run_length(r.run()))] += 1;
+                let (sym, _, _) = encode_distance(r.dist).expect("never none");
+                distance[usize::from(sym)] += 1;
+            }
+        }
+    }
+
+    (length, distance)
+}
+
+/// Code lengths for `freqs`, limited to `max_len`, the way zlib's `build_tree` does it.
+///
+/// A normal Huffman tree is built from a heap, ties broken by the depth of the subtree,
+/// then any codes which are too long are shortened by pushing other codes down, as in
+/// `gen_bitlen`. Like zlib, if fewer than two symbols are used, dummy symbols are added,
+/// so the resulting code is always complete.
+pub fn zlib_lengths(freqs: &[u32], max_len: u8) -> Vec<u8> {
+    let elems = freqs.len();
+    assert!(elems >= 3, "need room for the dummy symbols");
+
+    // leaves are `0..elems`, internal nodes follow
+    let mut freq: Vec<u64> = freqs.iter().map(|&f| u64::from(f)).collect();
+    freq.resize(2 * elems, 0);
+    let mut depth = vec![0u8; 2 * elems];
+    let mut dad = vec![0usize; 2 * elems];
+
+    // a 1-indexed binary heap in `heap[1..=heap_len]`; nodes removed from the heap
+    // are stored, in order, downwards from the end
+    let mut heap = vec![0usize; 2 * elems + 1];
+    let mut heap_len = 0;
+    let mut heap_max = heap.len();
+
+    let mut max_code = None;
+    for (n, &f) in freqs.iter().enumerate() {
+        if 0 != f {
+            heap_len += 1;
+            heap[heap_len] = n;
+            max_code = Some(n);
+        }
+    }
+
+    while heap_len < 2 {
+        let node = match max_code {
+            None => 0,
+            Some(code) if code < 2 => code + 1,
+            Some(_) => 0,
+        };
+        max_code = Some(max_code.map_or(node, |code| code.max(node)));
+        freq[node] = 1;
+        heap_len += 1;
+        heap[heap_len] = node;
+    }
+
+    let smaller = |freq: &[u64], depth: &[u8], n: usize, m: usize| {
+        freq[n] < freq[m] || (freq[n] == freq[m] && depth[n] <= depth[m])
+    };
+
+    let down_heap = |heap: &mut [usize], heap_len: usize, freq: &[u64], depth: &[u8], k: usize| {
+        let v = heap[k];
+        let mut k = k;
+        let mut j = k << 1;
+        while j <= heap_len {
+            if j < heap_len && smaller(freq, depth, heap[j + 1], heap[j]) {
+                j += 1;
+            }
+
+            if smaller(freq, depth, v, heap[j]) {
+                break;
+            }
+
+            heap[k] = heap[j];
+            k = j;
+            j <<= 1;
+        }
+        heap[k] = v;
+    };
+
+    for k in (1..=heap_len / 2).rev() {
+        down_heap(&mut heap, heap_len, &freq, &depth, k);
+    }
+
+    let mut node = elems;
+    while heap_len >= 2 {
+        let n = heap[1];
+        heap[1] = heap[heap_len];
+        heap_len -= 1;
+        down_heap(&mut heap, heap_len, &freq, &depth, 1);
+        let m = heap[1];
+
+        heap_max -= 1;
+        heap[heap_max] = n;
+        heap_max -= 1;
+        heap[heap_max] = m;
+
+        freq[node] = freq[n] + freq[m];
+        depth[node] = depth[n].max(depth[m]) + 1;
+        dad[n] = node;
+        dad[m] = node;
+
+        heap[1] = node;
+        down_heap(&mut heap, heap_len, &freq, &depth, 1);
+        node += 1;
+    }
+
+    heap_max -= 1;
+    heap[heap_max] = heap[1];
+
+    // gen_bitlen: walk from the root outwards, giving each node its parent's length, plus one
+    let mut len = vec![0u8; 2 * elems];
+    let mut bl_count = vec![0usize; usize::from(max_len) + 1];
+    let mut overflow = 0usize;
+
+    for &n in &heap[heap_max + 1..] {
+        let mut bits = len[dad[n]] + 1;
+        if bits > max_len {
+            bits = max_len;
+            overflow += 1;
+        }
+        len[n] = bits;
+
+        if n < elems {
+            bl_count[usize::from(bits)] += 1;
+        }
+    }
+
+    if 0 != overflow {
+        let max_len = usize::from(max_len);
+
+        // move a leaf from the deepest non-full level down, making room for two overflowed leaves
+        while overflow > 0 {
+            let mut bits = max_len - 1;
+            while 0 == bl_count[bits] {
+                bits -= 1;
+            }
+            bl_count[bits] -= 1;
+            bl_count[bits + 1] += 2;
+            bl_count[max_len] -= 1;
+            overflow = overflow.saturating_sub(2);
+        }
+
+        // reassign the lengths, longest to the least frequent
+        let mut leaves = heap[heap_max..].iter().rev().filter(|&&n| n < elems);
+        for bits in (1..=max_len).rev() {
+            for _ in 0..bl_count[bits] {
+                let n = *leaves.next().expect("counts match leaves");
+                len[n] = bits as u8;
+            }
+        }
+    }
+
+    len.truncate(elems);
+    len
+}
+
+/// Optimal code lengths for `freqs`, limited to `max_len`, using the package-merge algorithm.
+///
+/// Unused symbols get no code. A single used symbol gets a one bit code.
+pub fn package_merge_lengths(freqs: &[u32], max_len: u8) -> Vec<u8> {
+    let mut lengths = vec![0u8; freqs.len()];
+
+    let mut leaves: Vec<(u64, Vec<usize>)> = freqs
+        .iter()
+        .enumerate()
+        .filter(|&(_, &freq)| 0 != freq)
+        .map(|(sym, &freq)| (u64::from(freq), vec![sym]))
+        .collect();
+
+    match leaves.len() {
+        0 => return lengths,
+        1 => {
+            lengths[leaves[0].1[0]] = 1;
+            return lengths;
+        }
+        used => assert!(
+            used <= 1 << max_len,
+            "{} symbols can't fit in {} bits",
+            used,
+            max_len
+        ),
+    }
+
+    leaves.sort_by_key(|&(freq, ref syms)| (freq, syms[0]));
+
+    let mut list = leaves.clone();
+    for _ in 1..max_len {
+        let packages = list.chunks_exact(2).map(|pair| {
+            let mut syms = pair[0].1.clone();
+            syms.extend(&pair[1].1);
+            (pair[0].0 + pair[1].0, syms)
+        });
+
+        // a stable merge; leaves win ties
+        let mut merged = Vec::with_capacity(list.len());
+        let mut packages = packages.peekable();
+        let mut singles = leaves.iter().cloned().peekable();
+        loop {
+            let take_single = match (singles.peek(), packages.peek()) {
+                (Some(single), Some(package)) => single.0 <= package.0,
+                (Some(_), None) => true,
+                (None, Some(_)) => false,
+                (None, None) => break,
+            };
+
+            merged.push(if take_single {
+                singles.next().unwrap()
+            } else {
+                packages.next().unwrap()
+            });
+        }
+
+        list = merged;
+    }
+
+    for (_, syms) in &list[..2 * leaves.len() - 2] {
+        for &sym in syms {
+            lengths[sym] += 1;
+        }
+    }
+
+    lengths
+}
+
+/// The canonical code for each symbol (as in RFC 1951 section 3.2.2), or zero if it has no length.
+pub fn canonical_codes(lengths: &[u8]) -> Vec<u16> {
+    let mut bl_count = [0u16; 16];
+    for &len in lengths {
+        bl_count[usize::from(len)] += 1;
+    }
+    bl_count[0] = 0;
+
+    let mut next_code = [0u16; 16];
+    let mut code = 0u16;
+    for bits in 1..16 {
+        code = (code + bl_count[bits - 1]) << 1;
+        next_code[bits] = code;
+    }
+
+    lengths
+        .iter()
+        .map(|&len| {
+            if 0 == len {
+                return 0;
+            }
+            let code = next_code[usize::from(len)];
+            next_code[usize::from(len)] += 1;
+            code
+        })
+        .collect()
+}
+
+/// Serialise the header of a dynamic block, as read by `read_codes`, like zlib's `send_all_trees`.
+///
+/// Trailing unused symbols are trimmed, but `length` must include the end of block marker.
+pub fn write_trees(length: &[u8], distance: &[u8]) -> BitVec {
+    assert!(length.len() > 256 && length.len() <= 288);
+    assert!(distance.len() <= 32);
+
+    let lit_len_count = trimmed_len(length, 257);
+    let distance_count = trimmed_len(distance, 1);
+
+    let mut runs = Vec::new();
+    run_length_encode(&length[..lit_len_count], &mut runs);
+    let mut distance = distance.to_vec();
+    if distance.is_empty() {
+        // "one distance code of zero bits means that there are no distance codes"
+        distance.push(0);
+    }
+    run_length_encode(&distance[..distance_count], &mut runs);
+
+    let mut freqs = [0u32; 19];
+    for &(sym, _, _) in &runs {
+        freqs[usize::from(sym)] += 1;
+    }
+
+    let code_len_lens = zlib_lengths(&freqs, MAX_CODE_LEN_CODE_LEN);
+    let code_len_codes = canonical_codes(&code_len_lens);
+
+    let code_len_count = (4..=19)
+        .rev()
+        .find(|&count| 0 != code_len_lens[CODE_LEN_ORDER[count - 1]])
+        .unwrap_or(4);
+
+    let mut into = BitVec::new();
+    push_bits(&mut into, 5, (lit_len_count - 257) as u16);
+    push_bits(&mut into, 5, (distance_count - 1) as u16);
+    push_bits(&mut into, 4, (code_len_count - 4) as u16);
+
+    for &sym in &CODE_LEN_ORDER[..code_len_count] {
+        push_bits(&mut into, 3, u16::from(code_len_lens[sym]));
+    }
+
+    for (sym, extra_bits, extra) in runs {
+        let sym = usize::from(sym);
+        push_code(&mut into, code_len_lens[sym], code_len_codes[sym]);
+        push_bits(&mut into, extra_bits, extra);
+    }
+
+    into
+}
+
+fn trimmed_len(lengths: &[u8], min: usize) -> usize {
+    lengths
+        .iter()
+        .rposition(|&len| 0 != len)
+        .map_or(0, |pos| pos + 1)
+        .max(min)
+}
+
+/// Encode code lengths into (symbol, extra bit count, extra bits), like zlib's `scan_tree`.
+fn run_length_encode(lengths: &[u8], into: &mut Vec<(u8, u8, u16)>) {
+    let mut prev = None;
+    let mut pos = 0;
+
+    while pos < lengths.len() {
+        let cur = lengths[pos];
+        let (max_count, min_count) = match (cur, prev) {
+            (0, _) => (138, 3),
+            (_, Some(prev)) if prev == cur => (6, 3),
+            _ => (7, 4),
+        };
+
+        let count = lengths[pos..]
+            .iter()
+            .take(max_count)
+            .take_while(|&&len| len == cur)
+            .count();
+        pos += count;
+
+        if count < min_count {
+            into.extend((0..count).map(|_| (cur, 0, 0)));
+        } else if 0 != cur {
+            let mut count = count;
+            if Some(cur) != prev {
+                into.push((cur, 0, 0));
+                count -= 1;
+            }
+            into.push((16, 2, (count - 3) as u16));
+        } else if count <= 10 {
+            into.push((17, 3, (count - 3) as u16));
+        } else {
+            into.push((18, 7, (count - 11) as u16));
+        }
+
+        prev = Some(cur);
+    }
+}
+
+/// Like `BitWriter::write_bits_val`: the low bit first.
+fn push_bits(into: &mut BitVec, bits: u8, val: u16) {
+    for i in 0..bits {
+        into.push(0 != (val & (1 << i)));
+    }
+}
+
+/// Huffman codes are written with their high bit first.
+fn push_code(into: &mut BitVec, len: u8, code: u16) {
+    for i in (0..len).rev() {
+        into.push(0 != (code & (1 << i)));
+    }
+}
+
 #[test]
 fn print_fixed_tree() {
     println!("{:?}", *FIXED_LENGTH_TREE);
 }
+
+#[cfg(test)]
+mod tests {
+    use std::io::Cursor;
+
+    use super::*;
+    use crate::gzip;
+    use crate::parse;
+    use crate::Block;
+
+    fn kraft_sum_is_one(lengths: &[u8]) -> bool {
+        let sum: u64 = lengths
+            .iter()
+            .filter(|&&len| 0 != len)
+            .map(|&len| 1 << (32 - len))
+            .sum();
+        sum == 1 << 32
+    }
+
+    fn cost(freqs: &[u32], lengths: &[u8]) -> u64 {
+        freqs
+            .iter()
+            .zip(lengths)
+            .map(|(&freq, &len)| u64::from(freq) * u64::from(len))
+            .sum()
+    }
+
+    fn tree_lengths(tree: &CodeTree, count: usize) -> Vec<u8> {
+        tree.invert()
+            .into_iter()
+            .take(count)
+            .map(|code| code.map_or(0, |code| code.len() as u8))
+            .collect()
+    }
+
+    #[test]
+    fn canonical_codes_match_code_tree() {
+        let lengths = tree_lengths(&FIXED_LENGTH_TREE, 288);
+        let codes = canonical_codes(&lengths);
+        for (sym, code) in FIXED_LENGTH_TREE.invert().into_iter().enumerate() {
+            let code = code.unwrap();
+            let mut expected = BitVec::new();
+            push_code(&mut expected, lengths[sym], codes[sym]);
+            assert_eq!(expected, code, "symbol {}", sym);
+        }
+    }
+
+    #[test]
+    fn simple_lengths() {
+        let freqs = [1, 1, 2, 4, 0];
+        assert_eq!(vec![3, 3, 2, 1, 0], zlib_lengths(&freqs, 15));
+        assert_eq!(vec![3, 3, 2, 1, 0], package_merge_lengths(&freqs, 15));
+    }
+
+    #[test]
+    fn too_few_symbols() {
+        assert_eq!(vec![1, 1, 0], zlib_lengths(&[0, 0, 0], 15));
+        assert_eq!(vec![1, 1, 0, 0], zlib_lengths(&[5, 0, 0, 0], 15));
+        assert_eq!(vec![0, 1, 1, 0], zlib_lengths(&[0, 5, 0, 0], 15));
+        assert_eq!(vec![1, 0, 0, 1], zlib_lengths(&[0, 0, 0, 5], 15));
+
+        assert_eq!(vec![0, 0, 0], package_merge_lengths(&[0, 0, 0], 15));
+        assert_eq!(vec![0, 1, 0], package_merge_lengths(&[0, 5, 0], 15));
+    }
+
+    #[test]
+    fn limited_lengths() {
+        // fibonacci frequencies make the deepest possible unlimited tree
+        let mut freqs = vec![1u32, 1];
+        while freqs.len() < 25 {
+            freqs.push(freqs[freqs.len() - 1] + freqs[freqs.len() - 2]);
+        }
+
+        assert_eq!(24, *zlib_lengths(&freqs, 30).iter().max().unwrap());
+
+        let zlib = zlib_lengths(&freqs, 7);
+        let merged = package_merge_lengths(&freqs, 7);
+
+        for lengths in &[&zlib, &merged] {
+            assert_eq!(7, *lengths.iter().max().unwrap());
+            assert!(kraft_sum_is_one(lengths), "{:?}", lengths);
+        }
+
+        assert!(cost(&freqs, &merged) <= cost(&freqs, &zlib));
+    }
+
+    #[test]
+    fn trees_round_trip() {
+        // plenty of unused symbols, including a long run at the end
+        let mut freqs: Vec<u32> = (0..286u32).map(|sym| (sym * 7919) % 113).collect();
+        freqs[256] = 1;
+        freqs[270..].iter_mut().for_each(|freq| *freq = 0);
+        let length = zlib_lengths(&freqs, MAX_CODE_LEN);
+        let distance = zlib_lengths(&[3, 0, 0, 9, 1, 1, 0, 0, 0, 40], MAX_CODE_LEN);
+
+        let trees = write_trees(&length, &distance);
+        let (read_length, read_distance) = read_codes(&mut trees.iter()).unwrap();
+
+        assert_eq!(length, tree_lengths(&read_length, length.len()));
+        assert_eq!(
+            distance,
+            tree_lengths(&read_distance.unwrap(), distance.len())
+        );
+    }
+
+    #[test]
+    fn same_trees_as_gzip() {
+        let orig = &include_bytes!("../tests/data/libcgi-untaint-email-perl_0.03.orig.tar.gz")[..];
+        let mut cursor = Cursor::new(orig);
+        gzip::discard_header(&mut cursor).unwrap();
+
+        let mut dynamic = 0;
+        for block in parse::parse_deflate(&mut cursor) {
+            let (trees, codes) = match block.unwrap() {
+                Block::DynamicHuffman { trees, codes } => (trees, codes),
+                _ => continue,
+            };
+            dynamic += 1;
+
+            let (length, distance) = read_codes(&mut trees.iter()).unwrap();
+            let length = tree_lengths(&length, 286);
+            let distance = tree_lengths(&distance.unwrap(), 30);
+            assert_eq!(trees, write_trees(&length, &distance));
+
+            let (length_freqs, distance_freqs) = frequencies(&codes);
+            assert_eq!(length, zlib_lengths(&length_freqs, MAX_CODE_LEN));
+            assert_eq!(distance, zlib_lengths(&distance_freqs, MAX_CODE_LEN));
+        }
+
+        assert_ne!(0, dynamic);
+    }
+}
@@ -4,24 +4,32 @@ pub mod all_refs;
 mod back_map;
 mod bestguess;
 mod bit;
+pub mod chain_hash;
 mod circles;
 mod code_tree;
-// TODO: unused
+pub mod compress;
+pub mod cost;
 pub mod filter;
 pub mod gzip;
-mod huffman;
+pub mod huffman;
 mod iters;
 mod lookahead;
-mod obscure;
+pub mod pack;
 mod parse;
 mod picker;
-// TODO: unused
+pub mod pristine;
+pub mod recover;
 pub mod serialise;
 pub mod serialise_trace;
-mod technique;
+pub mod tarball;
+pub mod technique;
+#[cfg(test)]
+#[path = "../tests/common/mod.rs"]
+mod test_data;
 pub mod trace;
 pub mod tracer;
 mod wams;
+mod zip;
 
 use more_asserts::assert_ge;
 use more_asserts::assert_le;
@@ -29,12 +37,16 @@ use more_asserts::assert_le;
 use crate::bit::BitVec;
 
 pub use crate::circles::CircularBuffer;
+pub use crate::compress::compress;
 pub use crate::parse::parse_deflate;
 pub use crate::serialise::compressed_block;
+pub use crate::serialise::compressed_stream;
 pub use crate::serialise::decompressed_block;
 pub use crate::serialise::decompressed_codes;
 pub use crate::technique::Config;
+pub use crate::technique::Scanner;
 pub use crate::technique::Technique;
+pub use crate::tracer::Tracer;
 
 #[derive(Copy, Clone, PartialEq, Eq)]
 pub struct Ref {
@@ -55,8 +67,6 @@ pub enum Block {
     DynamicHuffman { trees: BitVec, codes: Vec<Code> },
 }
 
-type Obscure = (usize, u16);
-
 pub trait DataLen {
     fn data_len(&self) -> usize;
 }
@@ -69,10 +79,6 @@ pub trait Looker: DataLen {
     fn best_candidate_better_than(&self, pos: usize, other: Option<u16>) -> (u8, Option<Ref>);
 }
 
-pub trait Guesser: DataLen {
-    fn codes(&self) -> Vec<Code>;
-}
-
 #[derive(Debug, Eq, PartialEq)]
 pub struct WindowSettings {
     window_size: u16,
@@ -0,0 +1,1120 @@
+use std::convert::TryFrom;
+use std::io;
+use std::io::Read;
+use std::io::Write;
+use std::ops::Range;
+
+use anyhow::anyhow;
+use anyhow::bail;
+use anyhow::ensure;
+use anyhow::Context;
+use anyhow::Error;
+use byteorder::LittleEndian as LE;
+use byteorder::ReadBytesExt;
+use byteorder::WriteBytesExt;
+use rayon::iter::IntoParallelIterator;
+use rayon::iter::ParallelBridge;
+use rayon::iter::ParallelIterator;
+
+use crate::bit::BitVec;
+use crate::bit::BitWriter;
+use crate::circles::CircularBuffer;
+use crate::filter;
+use crate::filter::FilterWrite;
+use crate::gzip;
+use crate::parse::parse_deflate;
+use crate::serialise::compressed_block;
+use crate::serialise::decompressed_block;
+use crate::serialise_trace;
+use crate::technique;
+use crate::technique::Constructor;
+use crate::tracer;
+use crate::tracer::Tracer;
+use crate::tracer::WINDOW_SIZE;
+use crate::zip;
+use crate::Block;
+use crate::Trace;
+
+const MAGIC: &[u8] = b"rezip\0";
+const VERSION: u8 = 5;
+
+const GZIP_MAGIC: &[u8] = &[0x1f, 0x8b];
+const GZIP_TRAILER_LEN: usize = 8;
+const ZLIB_HEADER_LEN: usize = 2;
+
+/// The technique we record for streams with no huffman blocks, where it doesn't matter.
+const DEFAULT_TECHNIQUE: &str = "gzip-6";
+
+#[derive(Copy, Clone, Debug, Eq, PartialEq)]
+pub enum Container {
+    Gzip,
+    Zip,
+    Zlib,
+}
+
+/// Everything needed to rebuild a compressed file, given its decompressed data.
+#[derive(Clone, Debug, Eq, PartialEq)]
+pub struct Metadata {
+    /// SHA-512 of the original file, which `unpack` checks it rebuilt.
+    pub compressed_hash: Vec<u8>,
+    /// SHA-512 of the decompressed data, which `unpack` checks it was given.
+    pub decompressed_hash: Vec<u8>,
+    pub segments: Vec<Segment>,
+}
+
+#[derive(Clone, Debug, Eq, PartialEq)]
+pub enum Segment {
+    /// Bytes which are stored verbatim, such as container headers and trailers.
+    Raw(Vec<u8>),
+    Deflate(Stream),
+}
+
+#[derive(Clone, Debug, Eq, PartialEq)]
+pub struct Stream {
+    /// The registered name of the technique the traces are relative to.
+    pub technique: String,
+    pub blocks: Vec<PackedBlock>,
+}
+
+#[derive(Clone, Debug, Eq, PartialEq)]
+pub enum PackedBlock {
+    Uncompressed {
+        len: u16,
+    },
+    FixedHuffman {
+        len: u64,
+        trace: Vec<Trace>,
+    },
+    DynamicHuffman {
+        trees: BitVec,
+        len: u64,
+        trace: Vec<Trace>,
+    },
+}
+
+/// The most a metadata file may claim to produce, checked as it is read, so a hostile one can't
+/// exhaust memory.
+///
+/// The `Default` is for metadata with nothing to check it against, like an upload; where the
+/// decompressed data is at hand, `Limits::unpacking` is never too small.
+#[derive(Copy, Clone, Debug, Eq, PartialEq)]
+pub struct Limits {
+    /// Decompressed bytes, across every block of every stream.
+    pub decompressed_len: u64,
+    /// Bytes stored verbatim, across every raw segment.
+    pub raw_len: u64,
+}
+
+impl Limits {
+    /// For metadata which will be unpacked against `decompressed_len` bytes: it can't rightly
+    /// claim any more than that. Raw segments are stored in the metadata, so are only as big
+    /// as it is.
+    pub fn unpacking(decompressed_len: u64) -> Limits {
+        Limits {
+            decompressed_len,
+            raw_len: u64::MAX,
+        }
+    }
+}
+
+impl Default for Limits {
+    fn default() -> Self {
+        Limits {
+            decompressed_len: 1 << 30,
+            raw_len: 1 << 30,
+        }
+    }
+}
+
+#[derive(Debug)]
+pub struct Verification {
+    pub metadata: Metadata,
+    pub compressed_len: usize,
+    pub decompressed_len: usize,
+    pub metadata_len: usize,
+
+    /// The offset of the first byte which differs between the original and the rebuilt file.
+    pub mismatch: Option<usize>,
+}
+
+/// Split a file into its container and `DEFLATE` streams, and trace every block.
+///
+/// Gzip (including multiple members), zlib and zip containers are understood; anything else
+/// is attempted as a raw `DEFLATE` stream. Returns the metadata, and the concatenation of
+/// every stream's decompressed data.
+///
+/// Streams, and the blocks within them, are traced in parallel, on the current `rayon` pool;
+/// the metadata is the same however many threads there are.
+pub fn pack(data: &[u8]) -> Result<(Metadata, Vec<u8>), Error> {
+    let mut packer = Packer::default();
+
+    match container(data) {
+        Some(Container::Gzip) => packer.gzip(data)?,
+        Some(Container::Zip) => packer.zip(data)?,
+        Some(Container::Zlib) => {
+            packer.raw(&data[..ZLIB_HEADER_LEN]);
+            let end = ZLIB_HEADER_LEN + packer.deflate(&data[ZLIB_HEADER_LEN..])?;
+            packer.raw(&data[end..]);
+        }
+        None => {
+            let end = packer
+                .deflate(data)
+                .with_context(|| anyhow!("unrecognised container, and not raw deflate"))?;
+            packer.raw(&data[end..]);
+        }
+    }
+
+    let segments = packer
+        .pending
+        .into_par_iter()
+        .map(|pending| {
+            Ok(match pending {
+                Pending::Raw(bytes) => Segment::Raw(bytes),
+                Pending::Deflate { blocks, range } => {
+                    Segment::Deflate(pack_stream(&blocks, &packer.decompressed[range])?)
+                }
+            })
+        })
+        .collect::<Result<Vec<Segment>, Error>>()?;
+
+    let metadata = Metadata {
+        compressed_hash: filter::sha512(data),
+        decompressed_hash: filter::sha512(&packer.decompressed),
+        segments,
+    };
+
+    Ok((metadata, packer.decompressed))
+}
+
+/// `pack`, on `threads` threads; `None` for one per CPU.
+pub fn pack_with_threads(
+    data: &[u8],
+    threads: Option<usize>,
+) -> Result<(Metadata, Vec<u8>), Error> {
+    with_threads(threads, || pack(data))
+}
+
+/// Run `work`, and any parallel work it does, on `threads` threads; `None` for one per CPU.
+pub fn with_threads<T, F>(threads: Option<usize>, work: F) -> Result<T, Error>
+where
+    T: Send,
+    F: FnOnce() -> Result<T, Error> + Send,
+{
+    let mut pool = rayon::ThreadPoolBuilder::new();
+    if let Some(threads) = threads {
+        pool = pool.num_threads(threads);
+    }
+
+    pool.build()?.install(work)
+}
+
+/// Rebuild the original file from its metadata, and its decompressed data.
+///
+/// Fails if the decompressed data isn't what was packed, or if the rebuilt file isn't the
+/// original, rather than returning something almost, but not quite, right.
+pub fn unpack(metadata: &Metadata, decompressed: &[u8]) -> Result<Vec<u8>, Error> {
+    ensure!(
+        filter::sha512(decompressed) == metadata.decompressed_hash,
+        "the decompressed data isn't what was packed"
+    );
+
+    let (out, hash) = rebuild(metadata, decompressed)?;
+    ensure!(
+        hash == metadata.compressed_hash,
+        "the rebuilt file isn't the original"
+    );
+
+    Ok(out)
+}
+
+/// `unpack`, without checking the hashes; returns the rebuilt file, and its hash.
+fn rebuild(metadata: &Metadata, decompressed: &[u8]) -> Result<(Vec<u8>, Vec<u8>), Error> {
+    let mut out = Vec::new();
+    let mut writer = FilterWrite::new(&mut out);
+    let mut pos = 0;
+
+    for segment in &metadata.segments {
+        match *segment {
+            Segment::Raw(ref bytes) => writer.write_all(bytes)?,
+            Segment::Deflate(ref stream) => {
+                pos = unpack_stream(stream, decompressed, pos, &mut writer)?;
+            }
+        }
+    }
+
+    ensure!(
+        decompressed.len() == pos,
+        "{} byte(s) of decompressed data were left over",
+        decompressed.len() - pos
+    );
+
+    let hash = writer.hash();
+    Ok((out, hash))
+}
+
+/// Check that a file can be rebuilt, bit-exactly, from its serialised metadata.
+pub fn verify(data: &[u8]) -> Result<Verification, Error> {
+    let (metadata, decompressed) = pack(data)?;
+
+    let mut serialised = Vec::new();
+    metadata.write(&mut serialised)?;
+
+    let metadata = Metadata::read_with_limits(
+        io::Cursor::new(&serialised),
+        Limits {
+            decompressed_len: decompressed.len() as u64,
+            raw_len: data.len() as u64,
+        },
+    )?;
+
+    // a mismatch is what we're looking for, so don't fail on one
+    let (rebuilt, _) = rebuild(&metadata, &decompressed)?;
+
+    Ok(Verification {
+        metadata,
+        compressed_len: data.len(),
+        decompressed_len: decompressed.len(),
+        metadata_len: serialised.len(),
+        mismatch: first_difference(data, &rebuilt),
+    })
+}
+
+fn first_difference(left: &[u8], right: &[u8]) -> Option<usize> {
+    left.iter()
+        .zip(right)
+        .position(|(l, r)| l != r)
+        .or_else(|| {
+            if left.len() != right.len() {
+                Some(left.len().min(right.len()))
+            } else {
+                None
+            }
+        })
+}
+
+/// Guess the container format from the first few bytes of a file.
+pub fn container(data: &[u8]) -> Option<Container> {
+    if data.starts_with(GZIP_MAGIC) {
+        Some(Container::Gzip)
+    } else if data.starts_with(zip::LOCAL_HEADER_MAGIC) {
+        Some(Container::Zip)
+    } else if is_zlib_header(data) {
+        Some(Container::Zlib)
+    } else {
+        None
+    }
+}
+
+fn is_zlib_header(data: &[u8]) -> bool {
+    data.len() >= ZLIB_HEADER_LEN
+        // deflate, with a window of at most 32kB
+        && 0x08 == (data[0] & 0x0f)
+        && (data[0] >> 4) <= 7
+        // no preset dictionary
+        && 0 == (data[1] & 0x20)
+        && 0 == (u16::from(data[0]) << 8 | u16::from(data[1])) % 31
+}
+
+/// Splits a file into segments, decompressing as it goes, but leaving the tracing until the end.
+#[derive(Default)]
+struct Packer {
+    pending: Vec<Pending>,
+    decompressed: Vec<u8>,
+}
+
+/// A stream's blocks, before they're traced.
+struct ParsedStream {
+    /// Each block, and where its data starts within the `decompressed` data.
+    blocks: Vec<(Block, usize)>,
+    decompressed: Vec<u8>,
+    /// The number of bytes the stream occupied.
+    len: usize,
+}
+
+enum Pending {
+    Raw(Vec<u8>),
+    Deflate {
+        /// Each block, and where its data starts within the stream's.
+        blocks: Vec<(Block, usize)>,
+        /// The stream's data, in the `Packer`'s `decompressed`.
+        range: Range<usize>,
+    },
+}
+
+impl Packer {
+    fn raw(&mut self, bytes: &[u8]) {
+        if bytes.is_empty() {
+            return;
+        }
+
+        if let Some(Pending::Raw(previous)) = self.pending.last_mut() {
+            previous.extend(bytes);
+            return;
+        }
+
+        self.pending.push(Pending::Raw(bytes.to_vec()));
+    }
+
+    /// Returns the number of bytes of `data` the stream occupied.
+    fn deflate(&mut self, data: &[u8]) -> Result<usize, Error> {
+        let stream = parse_stream(data)?;
+        let len = stream.len;
+        self.stream(stream);
+        Ok(len)
+    }
+
+    fn stream(&mut self, stream: ParsedStream) {
+        let start = self.decompressed.len();
+        self.decompressed.extend(stream.decompressed);
+        self.pending.push(Pending::Deflate {
+            blocks: stream.blocks,
+            range: start..self.decompressed.len(),
+        });
+    }
+
+    fn gzip(&mut self, data: &[u8]) -> Result<(), Error> {
+        let mut pos = 0;
+
+        while data[pos..].starts_with(GZIP_MAGIC) {
+            let member = match gzip_member(&data[pos..]) {
+                Ok(member) => member,
+                // we don't want to fail if there's some junk on the end which looks a bit like gzip
+                Err(_) if pos > 0 => break,
+                Err(e) => return Err(e),
+            };
+
+            let (header_len, stream) = member;
+
+            self.raw(&data[pos..pos + header_len]);
+            pos += header_len + stream.len;
+
+            self.stream(stream);
+
+            let trailer_end = (pos + GZIP_TRAILER_LEN).min(data.len());
+            self.raw(&data[pos..trailer_end]);
+            pos = trailer_end;
+        }
+
+        self.raw(&data[pos..]);
+
+        Ok(())
+    }
+
+    fn zip(&mut self, data: &[u8]) -> Result<(), Error> {
+        let mut pos = 0;
+
+        while let Some(header) = zip::local_header(&data[pos..]) {
+            self.raw(&data[pos..pos + header.len]);
+            pos += header.len;
+
+            if zip::METHOD_DEFLATE == header.method {
+                pos += self.deflate(&data[pos..])?;
+            } else {
+                match zip::stored_end(&header, pos).filter(|&end| end <= data.len()) {
+                    Some(end) => {
+                        self.raw(&data[pos..end]);
+                        pos = end;
+                    }
+                    None => break,
+                }
+            }
+
+            if header.has_data_descriptor() {
+                let end = pos + zip::data_descriptor_len(&data[pos..]);
+                self.raw(&data[pos..end]);
+                pos = end;
+            }
+        }
+
+        // the central directory, and anything else we don't understand
+        self.raw(&data[pos..]);
+
+        Ok(())
+    }
+}
+
+fn gzip_member(data: &[u8]) -> Result<(usize, ParsedStream), Error> {
+    let header_len = gzip::discard_header(data)?.len();
+    Ok((header_len, parse_stream(&data[header_len..])?))
+}
+
+fn parse_stream(data: &[u8]) -> Result<ParsedStream, Error> {
+    let mut decompressed = Vec::new();
+    let mut dictionary = CircularBuffer::new();
+    let mut blocks = Vec::new();
+
+    let mut it = parse_deflate(data);
+
+    for block in it.by_ref() {
+        let block = block?;
+        let start = decompressed.len();
+        decompressed_block(&mut decompressed, &mut dictionary, &block)?;
+        blocks.push((block, start));
+    }
+
+    Ok(ParsedStream {
+        blocks,
+        decompressed,
+        len: usize::try_from(it.consumed_bits() / 8)?,
+    })
+}
+
+/// Trace every block, in parallel.
+///
+/// Each block needs the encoder's state after the blocks before it, but that only depends on
+/// their codes, which we already have, so a `Tracer` can be caught up cheaply, and a copy of it
+/// handed to each block.
+fn pack_stream(blocks: &[(Block, usize)], decompressed: &[u8]) -> Result<Stream, Error> {
+    let name = detect_technique(blocks, decompressed);
+    let mut tracer = Tracer::new(lookup(&name)?);
+
+    let jobs = blocks.iter().enumerate().map(|(i, (block, start))| {
+        let end = blocks
+            .get(i + 1)
+            .map(|(_, end)| *end)
+            .unwrap_or_else(|| decompressed.len());
+        let (data, following) = (&decompressed[*start..end], &decompressed[end..]);
+
+        let before = tracer.clone();
+        match *block {
+            Block::Uncompressed(_) => tracer.skip_block(data, following),
+            Block::FixedHuffman(ref codes) | Block::DynamicHuffman { ref codes, .. } => {
+                tracer.follow_block(data, codes)
+            }
+        }
+
+        (i, before, block, data, following)
+    });
+
+    let mut packed: Vec<(usize, Result<PackedBlock, Error>)> = jobs
+        .par_bridge()
+        .map(|(i, mut tracer, block, data, following)| {
+            (i, pack_block(&mut tracer, block, data, following))
+        })
+        .collect();
+    packed.sort_by_key(|&(i, _)| i);
+
+    Ok(Stream {
+        technique: name,
+        blocks: packed
+            .into_iter()
+            .map(|(_, block)| block)
+            .collect::<Result<Vec<PackedBlock>, Error>>()?,
+    })
+}
+
+fn preroll(decompressed: &[u8], start: usize) -> &[u8] {
+    &decompressed[start.saturating_sub(WINDOW_SIZE)..start]
+}
+
+fn lookup(name: &str) -> Result<Constructor, Error> {
+    technique::lookup(name).ok_or_else(|| anyhow!("unknown technique: {:?}", name))
+}
+
+/// Pick the gzip level whose emulation best predicts the first huffman block.
+fn detect_technique(blocks: &[(Block, usize)], decompressed: &[u8]) -> String {
+    let (codes, start) = match blocks.iter().find_map(|(block, start)| match *block {
+        Block::FixedHuffman(ref codes) | Block::DynamicHuffman { ref codes, .. } => {
+            Some((codes, *start))
+        }
+        Block::Uncompressed(_) => None,
+    }) {
+        Some(found) => found,
+        None => return DEFAULT_TECHNIQUE.to_string(),
+    };
+
+    let len: usize = codes.iter().map(|c| usize::from(c.emitted_bytes())).sum();
+    let data = &decompressed[start..start + len];
+
+    let level = (1..=9)
+        .min_by_key(|&level| {
+            let trace = tracer::trace_gzip(level, preroll(decompressed, start), data, codes);
+            serialise_trace::write(&trace).len()
+        })
+        .expect("non-empty range");
+
+    format!("gzip-{}", level)
+}
+
+/// `tracer` is in the state after the blocks before this one.
+fn pack_block(
+    tracer: &mut Tracer,
+    block: &Block,
+    data: &[u8],
+    following: &[u8],
+) -> Result<PackedBlock, Error> {
+    let len = u64::try_from(data.len())?;
+
+    Ok(match *block {
+        Block::Uncompressed(_) => PackedBlock::Uncompressed {
+            len: u16::try_from(data.len())?,
+        },
+        Block::FixedHuffman(ref codes) => PackedBlock::FixedHuffman {
+            len,
+            trace: tracer.trace_block(data, following, codes),
+        },
+        Block::DynamicHuffman {
+            ref trees,
+            ref codes,
+        } => PackedBlock::DynamicHuffman {
+            trees: trees.clone(),
+            len,
+            trace: tracer.trace_block(data, following, codes),
+        },
+    })
+}
+
+/// Returns the new position in `decompressed`.
+fn unpack_stream<W: Write>(
+    stream: &Stream,
+    decompressed: &[u8],
+    mut pos: usize,
+    out: W,
+) -> Result<usize, Error> {
+    let mut tracer = Tracer::new(lookup(&stream.technique)?);
+    let mut writer = BitWriter::new(out);
+
+    // the encoder could look ahead into later blocks, but not into the next stream
+    let stream_end = stream
+        .blocks
+        .iter()
+        .try_fold(pos, |end, packed| {
+            end.checked_add(usize::try_from(packed.decompressed_len()).ok()?)
+        })
+        .filter(|&end| end <= decompressed.len())
+        .ok_or_else(|| anyhow!("not enough decompressed data for stream"))?;
+
+    for (i, packed) in stream.blocks.iter().enumerate() {
+        let end = pos
+            .checked_add(usize::try_from(packed.decompressed_len())?)
+            .filter(|&end| end <= decompressed.len())
+            .ok_or_else(|| anyhow!("not enough decompressed data for block {}", i))?;
+
+        let data = &decompressed[pos..end];
+        let following = &decompressed[end..stream_end];
+
+        let block = match *packed {
+            PackedBlock::Uncompressed { .. } => {
+                tracer.skip_block(data, following);
+                Block::Uncompressed(data.to_vec())
+            }
+            PackedBlock::FixedHuffman { ref trace, .. } => Block::FixedHuffman(
+                tracer
+                    .restore_block(data, following, trace)
+                    .with_context(|| anyhow!("restoring block {}", i))?,
+            ),
+            PackedBlock::DynamicHuffman {
+                ref trees,
+                ref trace,
+                ..
+            } => Block::DynamicHuffman {
+                trees: trees.clone(),
+                codes: tracer
+                    .restore_block(data, following, trace)
+                    .with_context(|| anyhow!("restoring block {}", i))?,
+            },
+        };
+
+        writer.write_bit(i + 1 == stream.blocks.len())?;
+        compressed_block(&mut writer, &block)?;
+
+        pos = end;
+    }
+
+    writer.align()?;
+
+    Ok(pos)
+}
+
+impl PackedBlock {
+    /// The number of decompressed bytes in the block.
+    pub fn decompressed_len(&self) -> u64 {
+        match *self {
+            PackedBlock::Uncompressed { len } => u64::from(len),
+            PackedBlock::FixedHuffman { len, .. } | PackedBlock::DynamicHuffman { len, .. } => len,
+        }
+    }
+
+    pub fn trace(&self) -> Option<&[Trace]> {
+        match *self {
+            PackedBlock::Uncompressed { .. } => None,
+            PackedBlock::FixedHuffman { ref trace, .. }
+            | PackedBlock::DynamicHuffman { ref trace, .. } => Some(trace),
+        }
+    }
+}
+
+impl Metadata {
+    pub fn write<W: Write>(&self, mut into: W) -> Result<(), Error> {
+        into.write_all(MAGIC)?;
+        into.write_u8(VERSION)?;
+        ensure!(
+            filter::HASH_LEN == self.compressed_hash.len()
+                && filter::HASH_LEN == self.decompressed_hash.len(),
+            "hashes must be SHA-512"
+        );
+        into.write_all(&self.compressed_hash)?;
+        into.write_all(&self.decompressed_hash)?;
+        into.write_u32::<LE>(u32::try_from(self.segments.len())?)?;
+
+        for segment in &self.segments {
+            match *segment {
+                Segment::Raw(ref bytes) => {
+                    into.write_u8(0)?;
+                    write_bytes(&mut into, bytes)?;
+                }
+                Segment::Deflate(ref stream) => {
+                    into.write_u8(1)?;
+                    into.write_u8(u8::try_from(stream.technique.len())?)?;
+                    into.write_all(stream.technique.as_bytes())?;
+                    into.write_u32::<LE>(u32::try_from(stream.blocks.len())?)?;
+                    for block in &stream.blocks {
+                        write_block(&mut into, block)?;
+                    }
+                }
+            }
+        }
+
+        Ok(())
+    }
+
+    /// `read_with_limits`, with the default `Limits`.
+    pub fn read<R: Read>(from: R) -> Result<Metadata, Error> {
+        Metadata::read_with_limits(from, Limits::default())
+    }
+
+    /// Malformed or truncated input, or input which claims more than the `limits`, is an error.
+    pub fn read_with_limits<R: Read>(mut from: R, limits: Limits) -> Result<Metadata, Error> {
+        let mut magic = [0u8; 6];
+        from.read_exact(&mut magic)?;
+        ensure!(MAGIC == magic, "not a rezip metadata file");
+
+        let version = from.read_u8()?;
+        ensure!(VERSION == version, "unsupported version: {}", version);
+
+        let mut compressed_hash = vec![0u8; filter::HASH_LEN];
+        from.read_exact(&mut compressed_hash)?;
+        let mut decompressed_hash = vec![0u8; filter::HASH_LEN];
+        from.read_exact(&mut decompressed_hash)?;
+
+        let segment_count = from.read_u32::<LE>()?;
+        let mut segments = Vec::new();
+        let mut remaining = limits;
+
+        for _ in 0..segment_count {
+            segments.push(match from.read_u8()? {
+                0 => {
+                    let bytes = read_bytes(&mut from, remaining.raw_len)
+                        .with_context(|| anyhow!("reading raw segment"))?;
+                    remaining.raw_len -= bytes.len() as u64;
+                    Segment::Raw(bytes)
+                }
+                1 => {
+                    let mut technique = vec![0u8; usize::from(from.read_u8()?)];
+                    from.read_exact(&mut technique)?;
+                    let technique = String::from_utf8(technique)?;
+
+                    let block_count = from.read_u32::<LE>()?;
+                    ensure!(block_count > 0, "streams must have at least one block");
+
+                    let mut blocks = Vec::new();
+                    for i in 0..block_count {
+                        let block = read_block(&mut from, remaining.decompressed_len)
+                            .with_context(|| anyhow!("reading block {}", i))?;
+                        remaining.decompressed_len -= block.decompressed_len();
+                        blocks.push(block);
+                    }
+
+                    Segment::Deflate(Stream { technique, blocks })
+                }
+                other => bail!("invalid segment type: {}", other),
+            });
+        }
+
+        Ok(Metadata {
+            compressed_hash,
+            decompressed_hash,
+            segments,
+        })
+    }
+}
+
+fn write_block<W: Write>(mut into: W, block: &PackedBlock) -> Result<(), Error> {
+    match *block {
+        PackedBlock::Uncompressed { len } => {
+            into.write_u8(0)?;
+            into.write_u16::<LE>(len)?;
+        }
+        PackedBlock::FixedHuffman { len, ref trace } => {
+            into.write_u8(1)?;
+            into.write_u64::<LE>(len)?;
+            write_bytes(&mut into, &serialise_trace::write(trace))?;
+        }
+        PackedBlock::DynamicHuffman {
+            ref trees,
+            len,
+            ref trace,
+        } => {
+            into.write_u8(2)?;
+            into.write_u16::<LE>(u16::try_from(trees.len())?)?;
+            into.write_all(trees.bytes())?;
+            into.write_u64::<LE>(len)?;
+            write_bytes(&mut into, &serialise_trace::write(trace))?;
+        }
+    }
+
+    Ok(())
+}
+
+/// `max_len` is the most decompressed data the block may claim.
+fn read_block<R: Read>(mut from: R, max_len: u64) -> Result<PackedBlock, Error> {
+    Ok(match from.read_u8()? {
+        0 => {
+            let len = from.read_u16::<LE>()?;
+            check_len(u64::from(len), max_len)?;
+            PackedBlock::Uncompressed { len }
+        }
+        1 => {
+            let len = read_len(&mut from, max_len)?;
+            PackedBlock::FixedHuffman {
+                len,
+                trace: read_trace(&mut from, len)?,
+            }
+        }
+        2 => {
+            let bits = usize::from(from.read_u16::<LE>()?);
+            let mut bytes = vec![0u8; bits.div_ceil(8)];
+            from.read_exact(&mut bytes)?;
+            let mut trees = BitVec::from_slice(&bytes);
+            while trees.len() > bits {
+                trees.pop();
+            }
+
+            let len = read_len(&mut from, max_len)?;
+            PackedBlock::DynamicHuffman {
+                trees,
+                len,
+                trace: read_trace(&mut from, len)?,
+            }
+        }
+        other => bail!("invalid block type: {}", other),
+    })
+}
+
+fn read_len<R: Read>(mut from: R, max_len: u64) -> Result<u64, Error> {
+    let len = from.read_u64::<LE>()?;
+    check_len(len, max_len)?;
+    Ok(len)
+}
+
+fn check_len(len: u64, max_len: u64) -> Result<(), Error> {
+    ensure!(
+        len <= max_len,
+        "block claims {} decompressed byte(s), over the limit",
+        len
+    );
+    Ok(())
+}
+
+/// The trace of a block of `len` bytes: every code emits at least a byte, and takes at most
+/// three to serialise.
+fn read_trace<R: Read>(mut from: R, len: u64) -> Result<Vec<Trace>, Error> {
+    let bytes = read_bytes(&mut from, len.saturating_mul(3))?;
+    serialise_trace::read_at_most(io::Cursor::new(bytes), usize::try_from(len)?)
+}
+
+fn write_bytes<W: Write>(mut into: W, bytes: &[u8]) -> Result<(), Error> {
+    into.write_u64::<LE>(u64::try_from(bytes.len())?)?;
+    into.write_all(bytes)?;
+    Ok(())
+}
+
+/// Only allocates as much as is actually there, however long the bytes claim to be.
+fn read_bytes<R: Read>(mut from: R, max_len: u64) -> Result<Vec<u8>, Error> {
+    let len = from.read_u64::<LE>()?;
+    ensure!(len <= max_len, "{} byte(s) is over the limit", len);
+
+    let mut bytes = Vec::new();
+    from.take(len).read_to_end(&mut bytes)?;
+    ensure!(
+        bytes.len() as u64 == len,
+        "expected {} byte(s), but only {} remain",
+        len,
+        bytes.len()
+    );
+    Ok(bytes)
+}
+
+#[cfg(test)]
+mod tests {
+    use std::io::Write;
+
+    use flate2::write::DeflateEncoder;
+    use flate2::write::GzEncoder;
+    use flate2::write::ZlibEncoder;
+    use flate2::Compression;
+
+    use super::*;
+    use crate::test_data;
+    use crate::Ref;
+
+    fn assert_verifies(data: &[u8]) -> Verification {
+        let verification = verify(data).unwrap();
+        assert_eq!(None, verification.mismatch);
+        verification
+    }
+
+    #[test]
+    fn gzip_files() {
+        for file in &[
+            &include_bytes!("../tests/data/lol.gz")[..],
+            &include_bytes!("../tests/data/seq-20.gz")[..],
+            &include_bytes!("../tests/data/like-love.gz")[..],
+            &include_bytes!("../tests/data/libcgi-untaint-email-perl_0.03.orig.tar.gz")[..],
+            &include_bytes!("../tests/data/librole-basic-perl_0.13-1.debian.tar.gz")[..],
+        ] {
+            assert_verifies(file);
+        }
+    }
+
+    #[test]
+    fn multiple_members_and_junk() {
+        let mut data = include_bytes!("../tests/data/lol.gz").to_vec();
+        data.extend(&include_bytes!("../tests/data/like-love.gz")[..]);
+        data.extend(b"\x1f\x8bjunk");
+
+        let verification = assert_verifies(&data);
+        assert_eq!(3 + 29, verification.decompressed_len);
+        assert_eq!(
+            2,
+            verification
+                .metadata
+                .segments
+                .iter()
+                .filter(|s| match s {
+                    Segment::Deflate(_) => true,
+                    Segment::Raw(_) => false,
+                })
+                .count()
+        );
+    }
+
+    #[test]
+    fn zlib_and_stored() {
+        let mut encoder = ZlibEncoder::new(Vec::new(), Compression::none());
+        encoder.write_all(&[b'a'; 100_000]).unwrap();
+        let compressed = encoder.finish().unwrap();
+
+        let verification = assert_verifies(&compressed);
+        assert_eq!(100_000, verification.decompressed_len);
+    }
+
+    #[test]
+    fn zip_entries() {
+        let content = b"hello, hello, hello, world";
+        let mut encoder = DeflateEncoder::new(Vec::new(), Compression::default());
+        encoder.write_all(content).unwrap();
+        let deflated = encoder.finish().unwrap();
+
+        let mut data = Vec::new();
+        for (method, body) in &[(8u16, &deflated[..]), (0, &content[..])] {
+            data.extend(zip::LOCAL_HEADER_MAGIC);
+            data.write_u16::<LE>(20).unwrap(); // version
+            data.write_u16::<LE>(0).unwrap(); // flags
+            data.write_u16::<LE>(*method).unwrap();
+            data.extend(&[0u8; 8]); // time, date, crc
+            data.write_u32::<LE>(body.len() as u32).unwrap();
+            data.write_u32::<LE>(content.len() as u32).unwrap();
+            data.write_u16::<LE>(1).unwrap(); // name length
+            data.write_u16::<LE>(0).unwrap(); // extra length
+            data.push(b'a');
+            data.extend(*body);
+        }
+        data.extend(b"PK\x01\x02 and a central directory");
+
+        let verification = assert_verifies(&data);
+        assert_eq!(content.len(), verification.decompressed_len);
+    }
+
+    #[test]
+    fn threads() {
+        let noise: Vec<u8> = test_data::noise()
+            .take(100_000)
+            .map(|n| b'0' + (n % 64) as u8)
+            .collect();
+
+        let mut data = Vec::new();
+        for level in &[1, 6, 0] {
+            let mut encoder = GzEncoder::new(Vec::new(), Compression::new(*level));
+            encoder.write_all(&noise).unwrap();
+            data.extend(encoder.finish().unwrap());
+        }
+
+        let (serial, decompressed) = pack_with_threads(&data, Some(1)).unwrap();
+        let blocks: usize = serial
+            .segments
+            .iter()
+            .map(|s| match *s {
+                Segment::Deflate(ref stream) => stream.blocks.len(),
+                Segment::Raw(_) => 0,
+            })
+            .sum();
+        assert!(blocks > 6, "{}", blocks);
+
+        let (parallel, _) = pack_with_threads(&data, Some(4)).unwrap();
+        assert_eq!(serial, parallel);
+        assert_eq!(data, unpack(&parallel, &decompressed).unwrap());
+    }
+
+    #[test]
+    fn metadata_round_trip() {
+        let data = &include_bytes!("../tests/data/librole-basic-perl_0.13-1.debian.tar.gz")[..];
+        let (metadata, decompressed) = pack(data).unwrap();
+        let mut serialised = Vec::new();
+        metadata.write(&mut serialised).unwrap();
+        assert_eq!(
+            metadata,
+            Metadata::read(io::Cursor::new(&serialised)).unwrap()
+        );
+        assert_eq!(data, unpack(&metadata, &decompressed).unwrap().as_slice());
+    }
+
+    #[test]
+    fn wrong_decompressed_data() {
+        let data = &include_bytes!("../tests/data/like-love.gz")[..];
+        let (metadata, mut decompressed) = pack(data).unwrap();
+        decompressed.push(b'!');
+        assert!(unpack(&metadata, &decompressed).is_err());
+    }
+
+    fn serialised(metadata: &Metadata) -> Vec<u8> {
+        let mut serialised = Vec::new();
+        metadata.write(&mut serialised).unwrap();
+        serialised
+    }
+
+    #[test]
+    fn truncated_metadata() {
+        let data = &include_bytes!("../tests/data/like-love.gz")[..];
+        let serialised = serialised(&pack(data).unwrap().0);
+        for len in 0..serialised.len() {
+            assert!(Metadata::read(&serialised[..len]).is_err(), "{}", len);
+        }
+    }
+
+    #[test]
+    fn limits() {
+        let data = &include_bytes!("../tests/data/librole-basic-perl_0.13-1.debian.tar.gz")[..];
+        let (metadata, decompressed) = pack(data).unwrap();
+        let serialised = serialised(&metadata);
+        let raw_len: usize = metadata
+            .segments
+            .iter()
+            .map(|s| match *s {
+                Segment::Raw(ref bytes) => bytes.len(),
+                Segment::Deflate(_) => 0,
+            })
+            .sum();
+
+        let exact = Limits {
+            decompressed_len: decompressed.len() as u64,
+            raw_len: raw_len as u64,
+        };
+        assert_eq!(
+            metadata,
+            Metadata::read_with_limits(&serialised[..], exact).unwrap()
+        );
+
+        for limits in &[
+            Limits {
+                decompressed_len: exact.decompressed_len - 1,
+                ..exact
+            },
+            Limits {
+                raw_len: exact.raw_len - 1,
+                ..exact
+            },
+        ] {
+            assert!(Metadata::read_with_limits(&serialised[..], *limits).is_err());
+        }
+
+        let unpacking = Limits::unpacking(decompressed.len() as u64);
+        assert_eq!(
+            metadata,
+            Metadata::read_with_limits(&serialised[..], unpacking).unwrap()
+        );
+        let short = Limits::unpacking(decompressed.len() as u64 - 1);
+        assert!(Metadata::read_with_limits(&serialised[..], short).is_err());
+
+        // a raw segment claiming far more than is there, or than could be allocated
+        let mut claims = serialised[..MAGIC.len() + 1 + 2 * filter::HASH_LEN].to_vec();
+        claims.write_u32::<LE>(1).unwrap();
+        claims.push(0);
+        claims.write_u64::<LE>(1 << 62).unwrap();
+        let limits = Limits {
+            raw_len: u64::MAX,
+            ..exact
+        };
+        assert!(Metadata::read_with_limits(&claims[..], limits).is_err());
+    }
+
+    #[test]
+    fn corrupt_traces() {
+        let data = &include_bytes!("../tests/data/like-love.gz")[..];
+        let (metadata, decompressed) = pack(data).unwrap();
+
+        let corruptions: [fn(&mut Vec<Trace>); 3] = [
+            |trace| trace.truncate(trace.len() / 2),
+            |trace| trace.insert(0, Trace::Actually(Ref::new(1, 258))),
+            |trace| trace.insert(0, Trace::Actually(Ref::new(32_768, 3))),
+        ];
+
+        for corrupt in &corruptions {
+            let mut metadata = metadata.clone();
+            for segment in &mut metadata.segments {
+                if let Segment::Deflate(ref mut stream) = *segment {
+                    match stream.blocks[0] {
+                        PackedBlock::FixedHuffman { ref mut trace, .. }
+                        | PackedBlock::DynamicHuffman { ref mut trace, .. } => corrupt(trace),
+                        PackedBlock::Uncompressed { .. } => unreachable!(),
+                    }
+                }
+            }
+
+            let metadata = Metadata::read(&serialised(&metadata)[..]).unwrap();
+            assert!(unpack(&metadata, &decompressed).is_err());
+        }
+    }
+
+    #[test]
+    fn hashes() {
+        let data = &include_bytes!("../tests/data/like-love.gz")[..];
+        let (metadata, decompressed) = pack(data).unwrap();
+        assert_eq!(filter::sha512(data), metadata.compressed_hash);
+        assert_eq!(filter::HASH_LEN, metadata.decompressed_hash.len());
+
+        let mut swapped = decompressed.clone();
+        swapped.swap(0, 1);
+        let err = unpack(&metadata, &swapped).unwrap_err();
+        assert!(err.to_string().contains("decompressed"), "{}", err);
+
+        // as if the metadata had been tampered with, in a way which still rebuilds something
+        let mut tampered = metadata.clone();
+        tampered.segments.push(Segment::Raw(b"junk".to_vec()));
+        let err = unpack(&tampered, &decompressed).unwrap_err();
+        assert!(err.to_string().contains("rebuilt"), "{}", err);
+
+        let mut tampered = metadata.clone();
+        tampered.decompressed_hash[0] ^= 1;
+        let serialised = serialised(&tampered);
+        let read = Metadata::read(&serialised[..]).unwrap();
+        assert!(unpack(&read, &decompressed).is_err());
+    }
+
+    #[test]
+    fn differences() {
+        assert_eq!(None, first_difference(b"abc", b"abc"));
+        assert_eq!(Some(1), first_difference(b"abc", b"aXc"));
+        assert_eq!(Some(2), first_difference(b"ab", b"abc"));
+    }
+}
@@ -1,4 +1,5 @@
 use std::io::Read;
+use std::mem;
 
 use anyhow::bail;
 use anyhow::ensure;
@@ -24,6 +25,13 @@ pub struct BlockIter<R: Read> {
     end: bool,
 }
 
+impl<R: Read> BlockIter<R> {
+    /// The number of bits consumed so far; after the last block, this includes the padding.
+    pub fn consumed_bits(&self) -> u64 {
+        self.inner.consumed_bits()
+    }
+}
+
 impl<R: Read> Iterator for BlockIter<R> {
     type Item = Result<Block, Error>;
 
@@ -45,17 +53,30 @@ impl<R: Read> Iterator for BlockIter<R> {
 }
 
 fn read_block<R: Read>(reader: &mut BitReader<R>) -> Result<Block, Error> {
+    read_block_salvaging(reader, &mut Vec::new())
+}
+
+/// Read a block, leaving any codes decoded before a failure in `codes`.
+///
+/// On success, `codes` is left empty, and the codes are moved into the returned `Block`.
+pub(crate) fn read_block_salvaging<R: Read>(
+    reader: &mut BitReader<R>,
+    codes: &mut Vec<Code>,
+) -> Result<Block, Error> {
     match reader.read_part(2)? {
         0 => {
             reader.align()?;
             reader.read_length_prefixed().map(Block::Uncompressed)
         }
-        1 => scan_huffman_data(
-            reader,
-            &huffman::FIXED_LENGTH_TREE,
-            Some(&huffman::FIXED_DISTANCE_TREE),
-        )
-        .map(Block::FixedHuffman),
+        1 => {
+            scan_huffman_data(
+                reader,
+                &huffman::FIXED_LENGTH_TREE,
+                Some(&huffman::FIXED_DISTANCE_TREE),
+                codes,
+            )?;
+            Ok(Block::FixedHuffman(mem::take(codes)))
+        }
         2 => {
             // scope-based borrow sigh
             let ((length, distance), trees) = {
@@ -63,8 +84,11 @@ fn read_block<R: Read>(reader: &mut BitReader<R>) -> Result<Block, Error> {
                 (huffman::read_codes(&mut tracker)?, tracker.into_data())
             };
 
-            scan_huffman_data(reader, &length, distance.as_ref())
-                .map(|codes| Block::DynamicHuffman { trees, codes })
+            scan_huffman_data(reader, &length, distance.as_ref(), codes)?;
+            Ok(Block::DynamicHuffman {
+                trees,
+                codes: mem::take(codes),
+            })
         }
         3 => bail!("reserved block type"),
         _ => unreachable!(),
@@ -75,9 +99,8 @@ fn scan_huffman_data<R: Read>(
     reader: &mut BitReader<R>,
     length: &CodeTree,
     distance: Option<&CodeTree>,
-) -> Result<Vec<Code>, Error> {
-    let mut ret = Vec::new();
-
+    ret: &mut Vec<Code>,
+) -> Result<(), Error> {
     loop {
         let sym = length.decode_symbol(reader)?;
 
@@ -109,7 +132,7 @@ fn scan_huffman_data<R: Read>(
         ret.push(Code::Reference(Ref::new(dist, run)));
     }
 
-    Ok(ret)
+    Ok(())
 }
 
 #[cfg(test)]
@@ -135,4 +158,48 @@ mod tests {
                 .collect::<Vec<Block>>()
         );
     }
+
+    /// A fixed block of `a`, then a reference back one, of length symbol 284 with `extra` bits.
+    fn fixed_284(extra: u16) -> Vec<u8> {
+        use crate::bit::BitWriter;
+
+        let mut writer = BitWriter::new(Vec::new());
+        writer.write_bit(true).unwrap();
+        writer.write_bits_val(2, 1).unwrap();
+
+        // huffman codes are written most significant bit first
+        let codes = [(8, 0x30 + u16::from(b'a')), (8, 0xc0 + 284 - 280)];
+        for (len, code) in codes {
+            for i in (0..len).rev() {
+                writer.write_bit(0 != (code >> i) & 1).unwrap();
+            }
+        }
+        writer.write_bits_val(5, extra).unwrap();
+
+        // distance symbol zero, then end of block
+        writer.write_bits_val(5, 0).unwrap();
+        writer.write_bits_val(7, 0).unwrap();
+        writer.align().unwrap();
+        writer.into_inner()
+    }
+
+    #[test]
+    fn non_canonical_run_length() {
+        let blocks: Vec<Block> = parse_deflate(Cursor::new(fixed_284(30)))
+            .map(|val| val.unwrap())
+            .collect();
+        assert_eq!(
+            vec![Block::FixedHuffman(vec![
+                Code::Literal(b'a'),
+                Code::Reference(Ref::new(1, 257)),
+            ])],
+            blocks
+        );
+
+        // 258 is in range, but would be written back out as symbol 285
+        let err = parse_deflate(Cursor::new(fixed_284(31)))
+            .find_map(Result::err)
+            .unwrap();
+        assert!(err.to_string().contains("non-canonical"), "{}", err);
+    }
 }
@@ -0,0 +1,337 @@
+//! Deltas in the layout pristine-tar uses for `.tar.gz` files, so they can be stored where its
+//! deltas are, and its deltas can be read, and replaced.
+//!
+//! A pristine-tar `gz` delta is a `.tar.gz` of small files: `version`, `type`, `params` (flags
+//! for its `zgz` compressor), `filename` and `timestamp` (from the gzip header), and, from
+//! version 3.0, `delta`, a binary diff from `zgz`'s output to the original. Ours have the same
+//! files, with our metadata in `rezip` instead of the diff, and our own `version`, which
+//! pristine-tar refuses, rather than rebuilding the wrong file from it.
+
+use std::collections::HashMap;
+use std::io;
+use std::io::Read;
+use std::path::Path;
+
+use anyhow::anyhow;
+use anyhow::bail;
+use anyhow::ensure;
+use anyhow::Context;
+use anyhow::Error;
+
+use crate::compress;
+use crate::filter;
+use crate::gzip;
+use crate::pack;
+use crate::pack::Container;
+use crate::pack::Limits;
+use crate::pack::Metadata;
+use crate::parse::parse_deflate;
+use crate::serialise::decompressed_block;
+use crate::technique::Config;
+use crate::Block;
+use crate::CircularBuffer;
+
+/// The `version` of the deltas we write.
+pub const VERSION: &str = "rezip";
+
+/// The only `type` of delta handled here.
+const TYPE: &str = "gz";
+
+/// The most `GzDelta::read` will decompress a delta to. A delta is a handful of small files,
+/// and a diff or our metadata, both of which are a small part of even a large tarball.
+pub const MAX_DELTA_LEN: usize = 256 << 20;
+
+#[derive(Clone, Debug, Eq, PartialEq)]
+pub struct GzDelta {
+    /// `2.0` or `3.0` for pristine-tar's deltas, or `VERSION` for ours.
+    pub version: String,
+    /// Flags for pristine-tar's `zgz`, separated by spaces; empty in ours, unless imported.
+    pub params: String,
+    /// The name in the gzip header, without any directories.
+    pub filename: String,
+    /// The modification time in the gzip header.
+    pub timestamp: u32,
+    /// pristine-tar's diff from `zgz`'s output to the original.
+    pub delta: Option<Vec<u8>>,
+    /// Our serialised `Metadata`.
+    pub rezip: Option<Vec<u8>>,
+}
+
+impl GzDelta {
+    /// Read a delta, ours or pristine-tar's, from its `.tar.gz`.
+    pub fn read(delta: &[u8]) -> Result<GzDelta, Error> {
+        GzDelta::read_with_limit(delta, MAX_DELTA_LEN)
+    }
+
+    /// `read`, failing if the delta decompresses to more than `max_len` bytes.
+    pub fn read_with_limit(delta: &[u8], max_len: usize) -> Result<GzDelta, Error> {
+        let tar = gunzip(delta, max_len).with_context(|| anyhow!("decompressing delta"))?;
+        let tar_len = tar.len() as u64;
+
+        let mut files = HashMap::new();
+        for entry in tar::Archive::new(io::Cursor::new(tar)).entries()? {
+            let entry = entry?;
+            let path = entry.path()?.to_string_lossy().to_string();
+            let name = path.trim_start_matches("./").to_string();
+            ensure!(
+                entry.size() <= tar_len,
+                "delta's {:?} is bigger than the delta",
+                name
+            );
+            let mut contents = Vec::new();
+            entry.take(tar_len).read_to_end(&mut contents)?;
+            files.insert(name, contents);
+        }
+
+        let text = |name: &str| -> Result<String, Error> {
+            let contents = files
+                .get(name)
+                .ok_or_else(|| anyhow!("delta has no {:?}", name))?;
+            let text = String::from_utf8(contents.clone())
+                .with_context(|| anyhow!("delta's {:?} isn't text", name))?;
+            Ok(text.trim_end_matches('\n').to_string())
+        };
+
+        let kind = text("type")?;
+        ensure!(TYPE == kind, "not a gz delta: {:?}", kind);
+
+        Ok(GzDelta {
+            version: text("version")?,
+            params: text("params")?,
+            filename: text("filename")?,
+            timestamp: text("timestamp")?
+                .parse()
+                .with_context(|| anyhow!("invalid timestamp"))?,
+            delta: files.get("delta").cloned(),
+            rezip: files.get("rezip").cloned(),
+        })
+    }
+
+    /// The delta's `.tar.gz`, laid out like pristine-tar's, and compressed with our own gzip.
+    pub fn write(&self) -> Result<Vec<u8>, Error> {
+        let mut builder = tar::Builder::new(Vec::new());
+
+        let fields = [
+            ("version", &self.version),
+            ("type", &TYPE.to_string()),
+            ("params", &self.params),
+            ("filename", &self.filename),
+            ("timestamp", &self.timestamp.to_string()),
+        ];
+        for (name, value) in &fields {
+            append(&mut builder, name, format!("{}\n", value).as_bytes())?;
+        }
+
+        if let Some(ref delta) = self.delta {
+            append(&mut builder, "delta", delta)?;
+        }
+        if let Some(ref rezip) = self.rezip {
+            append(&mut builder, "rezip", rezip)?;
+        }
+
+        let tar = builder.into_inner()?;
+        Ok(compress::gzip(&tar, Config::gzip_16_default()))
+    }
+}
+
+/// Describe how to rebuild `gz`, a gzip file, from `tar`, the data inside it.
+///
+/// Fails if the `gz` doesn't contain the `tar`, or can't be rebuilt from it.
+pub fn gendelta(gz: &[u8], tar: &[u8]) -> Result<GzDelta, Error> {
+    ensure!(
+        Some(Container::Gzip) == pack::container(gz),
+        "not a gzip file"
+    );
+    let (header, _) = gzip::read_header(gz)?;
+
+    let (metadata, decompressed) = pack::pack(gz)?;
+    ensure!(
+        filter::sha512(tar) == metadata.decompressed_hash,
+        "the gzip file contains {} bytes, but not this tar of {}",
+        decompressed.len(),
+        tar.len()
+    );
+
+    // unpacking checks it rebuilt the original
+    pack::unpack(&metadata, tar)?;
+
+    let mut rezip = Vec::new();
+    metadata.write(&mut rezip)?;
+
+    Ok(GzDelta {
+        version: VERSION.to_string(),
+        params: String::new(),
+        filename: header.name.as_ref().map_or_else(String::new, |name| {
+            let name = String::from_utf8_lossy(name);
+            Path::new(name.as_ref())
+                .file_name()
+                .map_or_else(String::new, |base| base.to_string_lossy().to_string())
+        }),
+        timestamp: header.mtime,
+        delta: None,
+        rezip: Some(rezip),
+    })
+}
+
+/// Replace one of pristine-tar's deltas with ours, given the `gz` it rebuilds, and the `tar`.
+///
+/// pristine-tar's `params` are kept, so the delta still says how it was first made.
+pub fn import(pristine: &GzDelta, gz: &[u8], tar: &[u8]) -> Result<GzDelta, Error> {
+    let mut ours = gendelta(gz, tar)?;
+    ensure!(
+        pristine.filename == ours.filename && pristine.timestamp == ours.timestamp,
+        "the delta is for {:?} at {}, but the gzip file is {:?} at {}",
+        pristine.filename,
+        pristine.timestamp,
+        ours.filename,
+        ours.timestamp
+    );
+    ours.params = pristine.params.clone();
+    Ok(ours)
+}
+
+/// Rebuild the gzip file from one of our deltas, and the `tar` inside it.
+pub fn gengz(delta: &GzDelta, tar: &[u8]) -> Result<Vec<u8>, Error> {
+    let rezip = match delta.rezip {
+        Some(ref rezip) => rezip,
+        None => bail!(
+            "a version {} delta needs pristine-tar to rebuild; import it first",
+            delta.version
+        ),
+    };
+
+    let metadata = Metadata::read_with_limits(&rezip[..], Limits::unpacking(tar.len() as u64))?;
+    pack::unpack(&metadata, tar)
+}
+
+fn append(builder: &mut tar::Builder<Vec<u8>>, name: &str, contents: &[u8]) -> Result<(), Error> {
+    // like a fresh file, but the same every time
+    let mut header = tar::Header::new_gnu();
+    header.set_path(name)?;
+    header.set_size(contents.len() as u64);
+    header.set_mode(0o644);
+    header.set_mtime(0);
+    header.set_cksum();
+    builder.append(&header, contents)?;
+    Ok(())
+}
+
+/// The contents of a (single member) gzip file, failing if it's more than `max_len` bytes.
+fn gunzip(data: &[u8], max_len: usize) -> Result<Vec<u8>, Error> {
+    let header_len = gzip::discard_header(data)?.len();
+
+    let mut decompressed = Vec::new();
+    let mut dictionary = CircularBuffer::new();
+    for block in parse_deflate(&data[header_len..]) {
+        let block = block?;
+        let len = match block {
+            Block::Uncompressed(ref bytes) => bytes.len(),
+            Block::FixedHuffman(ref codes) | Block::DynamicHuffman { ref codes, .. } => codes
+                .iter()
+                .map(|code| usize::from(code.emitted_bytes()))
+                .sum(),
+        };
+        // checked before decompressing, as a block can expand a lot
+        ensure!(
+            len <= max_len - decompressed.len(),
+            "decompresses to more than {} byte(s)",
+            max_len
+        );
+        decompressed_block(&mut decompressed, &mut dictionary, &block)?;
+    }
+
+    Ok(decompressed)
+}
+
+#[cfg(test)]
+mod tests {
+    use std::io::Write;
+
+    use flate2::write::GzEncoder;
+    use flate2::Compression;
+
+    use super::*;
+
+    const GZ: &[u8] = include_bytes!("../tests/data/libcgi-untaint-email-perl_0.03.orig.tar.gz");
+
+    /// A delta like pristine-tar would make, with its own `tar` and `gzip`.
+    fn pristine(filename: &str, timestamp: u32) -> Vec<u8> {
+        let mut builder = tar::Builder::new(Vec::new());
+        for (name, value) in &[
+            ("version", "2.0".to_string()),
+            ("type", "gz".to_string()),
+            ("params", "--gnu -9 -n".to_string()),
+            ("filename", filename.to_string()),
+            ("timestamp", timestamp.to_string()),
+        ] {
+            let mut header = tar::Header::new_ustar();
+            header.set_path(format!("./{}", name)).unwrap();
+            header.set_size(value.len() as u64 + 1);
+            header.set_cksum();
+            builder
+                .append(&header, format!("{}\n", value).as_bytes())
+                .unwrap();
+        }
+
+        let mut encoder = GzEncoder::new(Vec::new(), Compression::default());
+        encoder.write_all(&builder.into_inner().unwrap()).unwrap();
+        encoder.finish().unwrap()
+    }
+
+    #[test]
+    fn round_trip() {
+        let tar = gunzip(GZ, usize::MAX).unwrap();
+        let delta = gendelta(GZ, &tar).unwrap();
+        assert_eq!(VERSION, delta.version);
+
+        let (header, _) = gzip::read_header(GZ).unwrap();
+        assert_eq!(header.mtime, delta.timestamp);
+
+        let written = delta.write().unwrap();
+        assert_eq!(written, delta.write().unwrap());
+
+        let read = GzDelta::read(&written).unwrap();
+        assert_eq!(delta, read);
+        assert_eq!(GZ, gengz(&read, &tar).unwrap().as_slice());
+    }
+
+    #[test]
+    fn limit() {
+        let tar = gunzip(GZ, usize::MAX).unwrap();
+        let written = gendelta(GZ, &tar).unwrap().write().unwrap();
+        let len = gunzip(&written, usize::MAX).unwrap().len();
+
+        assert!(GzDelta::read_with_limit(&written, len).is_ok());
+        assert!(GzDelta::read_with_limit(&written, len - 1).is_err());
+
+        // a delta which is a little gzip, of a lot of zeros
+        let bomb = compress::gzip(&vec![0; 1 << 20], Config::gzip_16_default());
+        assert!(GzDelta::read_with_limit(&bomb, 1 << 16).is_err());
+    }
+
+    #[test]
+    fn wrong_tar() {
+        let mut tar = gunzip(GZ, usize::MAX).unwrap();
+        tar[100] ^= 1;
+        assert!(gendelta(GZ, &tar).is_err());
+    }
+
+    #[test]
+    fn pristine_tar_deltas() {
+        let tar = gunzip(GZ, usize::MAX).unwrap();
+        let ours = gendelta(GZ, &tar).unwrap();
+
+        let theirs = GzDelta::read(&pristine(&ours.filename, ours.timestamp)).unwrap();
+        assert_eq!("2.0", theirs.version);
+        assert_eq!("--gnu -9 -n", theirs.params);
+        assert_eq!(None, theirs.rezip);
+        assert!(gengz(&theirs, &tar).is_err());
+
+        let imported = import(&theirs, GZ, &tar).unwrap();
+        assert_eq!(theirs.params, imported.params);
+        assert_eq!(GZ, gengz(&imported, &tar).unwrap().as_slice());
+
+        let elsewhen = GzDelta::read(&pristine(&ours.filename, ours.timestamp + 1)).unwrap();
+        assert!(import(&elsewhen, GZ, &tar).is_err());
+    }
+}
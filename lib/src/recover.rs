@@ -0,0 +1,308 @@
+use std::convert::TryFrom;
+use std::io;
+use std::io::Write;
+
+use anyhow::Error;
+
+use crate::bit::BitReader;
+use crate::circles::CircularBuffer;
+use crate::parse::read_block_salvaging;
+use crate::Block;
+use crate::Code;
+
+/// Written in place of bytes which were referenced, but which we lost to the damage.
+const UNKNOWN_BYTE: u8 = 0;
+
+#[derive(Debug, Default)]
+pub struct Report {
+    /// Bytes written to the output, including any `unknown_bytes`.
+    pub recovered_bytes: u64,
+
+    /// Bytes written in place of references into history which was lost.
+    pub unknown_bytes: u64,
+
+    /// Blocks which decoded cleanly, from end to end.
+    pub complete_blocks: usize,
+
+    /// True if we successfully decoded a block marked as the last one.
+    pub finished: bool,
+
+    /// The bit offset at which we stopped reading, if we `finished`.
+    pub end_bit: Option<u64>,
+
+    pub damage: Vec<Damage>,
+}
+
+#[derive(Debug)]
+pub struct Damage {
+    /// The bit offset of the header of the block which failed to decode.
+    pub block_start: u64,
+
+    /// The bit offset at which decoding gave up.
+    pub failed_at: u64,
+
+    pub error: Error,
+
+    /// The bit offset of the plausible block header we carried on from, if any.
+    pub resumed_at: Option<u64>,
+}
+
+/// Decode as much of a raw `DEFLATE` stream as possible, writing the data to `into`.
+///
+/// Decoding does not stop at the first problem; the partial block is written out,
+/// and, if `resync` is set, we scan forward for something that looks like a valid
+/// block header, and continue from there. History is lost across a resync, so any
+/// reference into the lost data is filled with `UNKNOWN_BYTE`s.
+///
+/// Only fails if writing fails.
+pub fn salvage<W: Write>(data: &[u8], mut into: W, resync: bool) -> Result<Report, Error> {
+    let mut report = Report::default();
+    let mut dictionary = CircularBuffer::new();
+    let mut pos = 0u64;
+
+    while let Some(mut reader) = reader_at(data, pos) {
+        let mut codes = Vec::new();
+        let result = reader
+            .read_bit()
+            .and_then(|last| read_block_salvaging(&mut reader, &mut codes).map(|b| (last, b)));
+
+        // the reader started at the beginning of the byte containing `pos`
+        let block_end = pos - pos % 8 + reader.consumed_bits();
+
+        match result {
+            Ok((last, block)) => {
+                write_block(&mut into, &mut dictionary, &block, &mut report)?;
+                report.complete_blocks += 1;
+                pos = block_end;
+
+                if last {
+                    report.finished = true;
+                    report.end_bit = Some(pos);
+                    break;
+                }
+            }
+            Err(error) => {
+                write_codes(&mut into, &mut dictionary, &codes, &mut report)?;
+
+                let resumed_at = if resync && !is_eof(&error) {
+                    find_plausible_block(data, block_end)
+                } else {
+                    None
+                };
+
+                report.damage.push(Damage {
+                    block_start: pos,
+                    failed_at: block_end,
+                    error,
+                    resumed_at,
+                });
+
+                match resumed_at {
+                    Some(resumed_at) => {
+                        pos = resumed_at;
+                        dictionary = CircularBuffer::new();
+                    }
+                    None => break,
+                }
+            }
+        }
+    }
+
+    Ok(report)
+}
+
+fn reader_at(data: &[u8], bit: u64) -> Option<BitReader<&[u8]>> {
+    let byte = usize::try_from(bit / 8).ok()?;
+    if byte >= data.len() {
+        return None;
+    }
+
+    let mut reader = BitReader::new(&data[byte..]);
+    for _ in 0..bit % 8 {
+        reader.read_bit().ok()?;
+    }
+
+    Some(reader)
+}
+
+fn is_eof(error: &Error) -> bool {
+    error
+        .chain()
+        .filter_map(|cause| cause.downcast_ref::<io::Error>())
+        .any(|e| e.kind() == io::ErrorKind::UnexpectedEof)
+}
+
+/// Scan forward from `from`, bit by bit, for a block header that looks real.
+fn find_plausible_block(data: &[u8], from: u64) -> Option<u64> {
+    let end = u64::try_from(data.len()).ok()? * 8;
+    ((from + 1)..end).find(|&bit| plausible_block_at(data, bit))
+}
+
+/// Fixed huffman blocks parse successfully from random data surprisingly often, so
+/// we only trust them if they're the last block, or if another block follows them.
+/// Dynamic headers are complete trees, and stored blocks carry a check, so are unlikely
+/// to be accidental.
+fn plausible_block_at(data: &[u8], bit: u64) -> bool {
+    let mut reader = match reader_at(data, bit) {
+        Some(reader) => reader,
+        None => return false,
+    };
+
+    let mut codes = Vec::new();
+
+    let last = match reader.read_bit() {
+        Ok(last) => last,
+        Err(_) => return false,
+    };
+
+    match read_block_salvaging(&mut reader, &mut codes) {
+        Ok(Block::FixedHuffman(_)) => {
+            last || reader
+                .read_bit()
+                .and_then(|_| read_block_salvaging(&mut reader, &mut codes))
+                .is_ok()
+        }
+        Ok(Block::Uncompressed(_)) | Ok(Block::DynamicHuffman { .. }) => true,
+        Err(_) => false,
+    }
+}
+
+fn write_block<W: Write>(
+    into: &mut W,
+    dictionary: &mut CircularBuffer,
+    block: &Block,
+    report: &mut Report,
+) -> Result<(), Error> {
+    match *block {
+        Block::Uncompressed(ref data) => {
+            dictionary.extend(data);
+            into.write_all(data)?;
+            report.recovered_bytes += u64::try_from(data.len())?;
+            Ok(())
+        }
+        Block::FixedHuffman(ref codes) | Block::DynamicHuffman { ref codes, .. } => {
+            write_codes(into, dictionary, codes, report)
+        }
+    }
+}
+
+/// Like `decompressed_codes`, but tolerant of references to before the start of the history.
+fn write_codes<W: Write>(
+    into: &mut W,
+    dictionary: &mut CircularBuffer,
+    codes: &[Code],
+    report: &mut Report,
+) -> Result<(), Error> {
+    let mut buf = Vec::with_capacity(codes.len());
+
+    for code in codes {
+        match *code {
+            Code::Literal(byte) => {
+                dictionary.push(byte);
+                buf.push(byte);
+            }
+            Code::Reference(r) => {
+                for _ in 0..r.run() {
+                    let byte = if r.dist <= dictionary.len() {
+                        dictionary.get_at_dist(r.dist)
+                    } else {
+                        report.unknown_bytes += 1;
+                        UNKNOWN_BYTE
+                    };
+                    dictionary.push(byte);
+                    buf.push(byte);
+                }
+            }
+        }
+    }
+
+    into.write_all(&buf)?;
+    report.recovered_bytes += u64::try_from(buf.len())?;
+
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use std::io::Cursor;
+
+    use super::*;
+    use crate::gzip;
+
+    fn librole() -> (&'static [u8], Vec<u8>) {
+        // many blocks, so many not-byte-aligned block headers
+        let orig = &include_bytes!("../tests/data/librole-basic-perl_0.13-1.debian.tar.gz")[..];
+        let mut cursor = Cursor::new(orig);
+        let header = gzip::discard_header(&mut cursor).unwrap();
+        let deflate = &orig[header.len()..];
+
+        let mut expected = Vec::new();
+        let report = salvage(deflate, &mut expected, false).unwrap();
+        assert!(report.finished);
+        assert!(report.damage.is_empty());
+        assert_eq!(18, report.complete_blocks);
+        assert_eq!(20480, expected.len());
+
+        (deflate, expected)
+    }
+
+    #[test]
+    fn truncated() {
+        let (deflate, expected) = librole();
+        let truncated = &deflate[..deflate.len() / 2];
+
+        let mut recovered = Vec::new();
+        let report = salvage(truncated, &mut recovered, true).unwrap();
+
+        assert!(!report.finished);
+        assert_eq!(1, report.damage.len());
+        assert_eq!(None, report.damage[0].resumed_at);
+        assert!(report.damage[0].failed_at <= (truncated.len() as u64) * 8);
+        assert!(!recovered.is_empty());
+        assert_eq!(&expected[..recovered.len()], recovered.as_slice());
+    }
+
+    #[test]
+    fn reserved_block_type() {
+        let mut recovered = Vec::new();
+        let report = salvage(&[0b0000_0111], &mut recovered, false).unwrap();
+        assert!(recovered.is_empty());
+        assert_eq!(1, report.damage.len());
+        assert_eq!(0, report.damage[0].block_start);
+        assert_eq!(3, report.damage[0].failed_at);
+    }
+
+    #[test]
+    fn resync_after_garbage() {
+        // a non-final stored block, then a block of garbage, then a final stored block
+        let mut data = vec![0b0000_0000, 3, 0, 0xfc, 0xff, b'a', b'b', b'c'];
+        data.push(0b0000_0111);
+        data.extend(&[0b0000_0001, 3, 0, 0xfc, 0xff, b'd', b'e', b'f']);
+
+        let mut recovered = Vec::new();
+        let report = salvage(&data, &mut recovered, true).unwrap();
+        assert_eq!(b"abcdef", recovered.as_slice());
+        assert!(report.finished);
+        assert_eq!(2, report.complete_blocks);
+        assert_eq!(1, report.damage.len());
+        assert_eq!(8 * 8, report.damage[0].block_start);
+        assert_eq!(Some(9 * 8), report.damage[0].resumed_at);
+    }
+
+    #[test]
+    fn lost_history() {
+        let mut report = Report::default();
+        let mut into = Vec::new();
+        let mut dictionary = CircularBuffer::new();
+        write_codes(
+            &mut into,
+            &mut dictionary,
+            &[Code::Literal(b'a'), crate::Ref::new(2, 3).into()],
+            &mut report,
+        )
+        .unwrap();
+        assert_eq!(&[b'a', UNKNOWN_BYTE, b'a', UNKNOWN_BYTE], into.as_slice());
+        // the second unknown byte is copied from the first, so is not counted again
+        assert_eq!(1, report.unknown_bytes);
+    }
+}
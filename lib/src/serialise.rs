@@ -39,18 +39,25 @@ pub fn decompressed_codes<W: Write>(
 ) -> Result<(), Error> {
     use self::Code::*;
 
+    // runs of literals are written together
+    let mut literals = Vec::new();
+
     for code in codes {
         match *code {
-            Literal(byte) => {
-                dictionary.push(byte);
-                into.write_all(&[byte])?
-            }
+            Literal(byte) => literals.push(byte),
             Reference(r) => {
+                dictionary.extend(&literals);
+                into.write_all(&literals)?;
+                literals.clear();
+
                 dictionary.copy(r.dist, r.run(), &mut into)?;
             }
         }
     }
 
+    dictionary.extend(&literals);
+    into.write_all(&literals)?;
+
     Ok(())
 }
 
@@ -84,6 +91,19 @@ pub fn compressed_block<W: Write>(into: &mut BitWriter<W>, block: &Block) -> Res
     }
 }
 
+/// Write `blocks` as a whole `DEFLATE` stream, marking the last as final, then padding.
+pub fn compressed_stream<W: Write>(into: W, blocks: &[Block]) -> Result<W, Error> {
+    let mut writer = BitWriter::new(into);
+
+    for (id, block) in blocks.iter().enumerate() {
+        writer.write_bit(id + 1 == blocks.len())?;
+        compressed_block(&mut writer, block)?;
+    }
+
+    writer.align()?;
+    Ok(writer.into_inner())
+}
+
 pub struct Lengths {
     length: Vec<Option<u8>>,
     distance: Vec<Option<u8>>,
@@ -98,17 +118,22 @@ fn tree_to_lengths(tree: &CodeTree) -> Vec<Option<u8>> {
 }
 
 impl Lengths {
-    pub fn new(length_tree: &CodeTree, distance_tree: &CodeTree) -> Self {
+    /// A block without a distance tree can't contain any references.
+    pub fn new(length_tree: &CodeTree, distance_tree: Option<&CodeTree>) -> Self {
         let length = tree_to_lengths(length_tree);
         let all_lengths: usize = length.iter().filter_map(|x| x.map(usize::from)).sum();
         let populated_lengths: usize = 1 + length.iter().filter_map(|x| *x).count();
         Lengths {
             length,
-            distance: tree_to_lengths(distance_tree),
+            distance: distance_tree.map_or_else(|| vec![None; 32], tree_to_lengths),
             mean_literal_len: ((all_lengths + populated_lengths) / populated_lengths) as u8,
         }
     }
 
+    pub fn end_of_block(&self) -> u8 {
+        self.length[256].expect("every tree has an end of block")
+    }
+
     pub fn length(&self, code: &Code) -> Option<u8> {
         match *code {
             Code::Literal(byte) => self.length[usize::from(byte)],
@@ -126,7 +151,9 @@ impl Lengths {
                     None => return None,
                 };
 
-                Some(run_symbol_len + distance_symbol_len + bit_count)
+                let run_bit_count = huffman::extra_run_length(run).map_or(0, |(bits, _)| bits);
+
+                Some(run_symbol_len + run_bit_count + distance_symbol_len + bit_count)
             }
         }
     }
@@ -162,7 +189,11 @@ fn compressed_codes<W: Write>(
     }
 
     // End of stream marker
-    into.write_vec(length_tree[256].as_ref().unwrap())?;
+    into.write_vec(
+        length_tree[256]
+            .as_ref()
+            .ok_or_else(|| anyhow!("no end of block in tree"))?,
+    )?;
 
     Ok(())
 }
@@ -175,7 +206,7 @@ fn encode_run<W: Write>(
     into.write_vec(
         length_tree[huffman::encode_run_length(run) as usize]
             .as_ref()
-            .unwrap(),
+            .ok_or_else(|| anyhow!("invalid run length: {}", run))?,
     )?;
 
     if let Some((bits, val)) = huffman::extra_run_length(run) {
@@ -195,7 +226,11 @@ fn encode_distance<W: Write>(
             .as_ref()
             .ok_or_else(|| anyhow!("reference but not distance tree"))?;
 
-        into.write_vec(distance_tree[usize::from(code)].as_ref().unwrap())?;
+        into.write_vec(
+            distance_tree[usize::from(code)]
+                .as_ref()
+                .ok_or_else(|| anyhow!("invalid distance: {}", dist))?,
+        )?;
 
         if bits > 0 {
             into.write_bits_val(bits, val)?;
@@ -1,9 +1,11 @@
 use std::convert::TryFrom;
 use std::io;
 use std::io::Read;
+use std::iter;
 use std::u16;
 
 use anyhow::bail;
+use anyhow::ensure;
 use anyhow::Error;
 use byteorder::LittleEndian as LE;
 use byteorder::ReadBytesExt;
@@ -62,7 +64,12 @@ pub fn write(traces: &[Trace]) -> Vec<u8> {
     ret
 }
 
-pub fn read<R: Read>(mut data: R) -> Result<Vec<Trace>, Error> {
+pub fn read<R: Read>(data: R) -> Result<Vec<Trace>, Error> {
+    read_at_most(data, usize::MAX)
+}
+
+/// `read`, failing if there are more than `max` traces, before storing them.
+pub fn read_at_most<R: Read>(mut data: R, max: usize) -> Result<Vec<Trace>, Error> {
     let mut ret = Vec::new();
 
     loop {
@@ -72,17 +79,22 @@ pub fn read<R: Read>(mut data: R) -> Result<Vec<Trace>, Error> {
             Err(e) => bail!(e),
         };
 
+        let count = if first > 32_768 {
+            usize::from(first - 32_768)
+        } else {
+            1
+        };
+        ensure!(count <= max - ret.len(), "more than {} traces", max);
+
         if 0 == first {
             ret.push(Trace::ActuallyLiteral);
         } else if first <= 32_768 {
+            // both in range for `Ref::new`: the distance is 1 to 32,768, and the run 3 to 258
             let dist = first;
             let run_minus_3 = data.read_u8()?;
             ret.push(Trace::Actually(Ref::new(dist, u16::from(run_minus_3) + 3)));
         } else {
-            let count = first - 32_768;
-            for _ in 0..count {
-                ret.push(Trace::Correct);
-            }
+            ret.extend(iter::repeat_n(Trace::Correct, count));
         }
     }
 
@@ -116,4 +128,19 @@ mod tests {
             assert_round_trip(&v);
         }
     }
+
+    #[test]
+    fn at_most() {
+        let data = super::write(&[Trace::Correct; 100]);
+        assert_eq!(
+            100,
+            super::read_at_most(io::Cursor::new(&data), 100)
+                .unwrap()
+                .len()
+        );
+        assert!(super::read_at_most(io::Cursor::new(&data), 99).is_err());
+
+        // a truncated reference
+        assert!(super::read(io::Cursor::new(&[1u8, 0])).is_err());
+    }
 }
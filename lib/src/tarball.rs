@@ -0,0 +1,530 @@
+//! A layer under the gzip one, for `.tar.gz` files: records everything in the tar except the
+//! contents of its files, so the exact tar can be rebuilt from the directory it was extracted
+//! into, then the exact `.tar.gz` on top of that.
+
+use std::collections::HashMap;
+use std::collections::HashSet;
+use std::fs;
+use std::io;
+use std::io::Read;
+use std::io::Write;
+use std::path::Component;
+use std::path::Path;
+use std::path::PathBuf;
+
+use anyhow::anyhow;
+use anyhow::bail;
+use anyhow::ensure;
+use anyhow::Context;
+use anyhow::Error;
+use byteorder::LittleEndian as LE;
+use byteorder::ReadBytesExt;
+use byteorder::WriteBytesExt;
+use tar::EntryType;
+
+use crate::filter;
+use crate::pack;
+use crate::pack::Limits;
+use crate::pack::Metadata;
+
+const MAGIC: &[u8] = b"rezip-tar\0";
+const VERSION: u8 = 1;
+
+/// Everything needed to rebuild a tar, given the directory it was extracted into.
+///
+/// Stored uncompressed: it's mostly headers, which are mostly zeros, and whatever stores it,
+/// like git, will compress it anyway.
+#[derive(Clone, Debug, Eq, PartialEq)]
+pub struct TarMetadata {
+    /// SHA-512 of the tar, which `unpack` checks it rebuilt.
+    pub hash: Vec<u8>,
+    pub segments: Vec<TarSegment>,
+}
+
+#[derive(Clone, Debug, Eq, PartialEq)]
+pub enum TarSegment {
+    /// Bytes stored verbatim: headers, long names, padding, the end of the archive, and the
+    /// contents of anything which can't be found in the directory.
+    Raw(Vec<u8>),
+    /// The contents of a regular file, read from the directory.
+    File { path: String, len: u64 },
+}
+
+/// Split a tar into what's stored verbatim, and the files which will be in its directory.
+///
+/// Files are only taken from the directory if extracting the tar would have left them there:
+/// anything overwritten by a later entry, outside the directory, or sparse is stored verbatim.
+/// So is anything written through a link the tar made, or before one: where that lands depends
+/// on the extractor, and it could land on top of an earlier file.
+pub fn pack(tar: &[u8]) -> Result<TarMetadata, Error> {
+    let mut files = Vec::new();
+
+    let mut archive = tar::Archive::new(io::Cursor::new(tar));
+    for entry in archive.entries()? {
+        let mut entry = entry?;
+        let path = entry.path()?.to_str().map(str::to_string);
+
+        let sparse = entry.pax_extensions()?.is_some_and(|extensions| {
+            extensions
+                .filter_map(Result::ok)
+                .any(|extension| extension.key_bytes().starts_with(b"GNU.sparse"))
+        });
+
+        let regular = match entry.header().entry_type() {
+            EntryType::Regular | EntryType::Continuous => !sparse,
+            _ => false,
+        };
+
+        let link = matches!(
+            entry.header().entry_type(),
+            EntryType::Symlink | EntryType::Link
+        );
+
+        files.push((path, regular, link, entry.raw_file_position(), entry.size()));
+    }
+
+    // links, and the last entry written through one
+    let mut links = HashSet::new();
+    let mut last_aliased = None;
+    for (i, (path, _, link, _, _)) in files.iter().enumerate() {
+        if let Some(path) = path {
+            let path = normalise(path);
+            if path.ancestors().skip(1).any(|a| links.contains(a)) {
+                last_aliased = Some(i);
+            }
+            if *link {
+                links.insert(path);
+            }
+        }
+    }
+
+    // the last entry for each path is the one left in the directory
+    let mut last = HashMap::new();
+    for (i, (path, _, _, _, _)) in files.iter().enumerate() {
+        if let Some(path) = path {
+            last.insert(normalise(path), i);
+        }
+    }
+
+    let mut segments = Vec::new();
+    let mut pos = 0;
+
+    for (i, (path, regular, _, start, len)) in files.into_iter().enumerate() {
+        let path = match path {
+            Some(path)
+                if regular
+                    && len > 0
+                    && last[&normalise(&path)] == i
+                    && last_aliased.is_none_or(|aliased| i > aliased)
+                    && is_safe(&path) =>
+            {
+                path
+            }
+            _ => continue,
+        };
+
+        let start = usize::try_from(start)?;
+        let end = start
+            .checked_add(usize::try_from(len)?)
+            .filter(|&end| end <= tar.len())
+            .ok_or_else(|| anyhow!("{:?} runs off the end of the tar", path))?;
+
+        push_raw(&mut segments, &tar[pos..start]);
+        segments.push(TarSegment::File { path, len });
+        pos = end;
+    }
+
+    push_raw(&mut segments, &tar[pos..]);
+
+    Ok(TarMetadata {
+        hash: filter::sha512(tar),
+        segments,
+    })
+}
+
+/// Rebuild the tar from the directory it was extracted into.
+///
+/// Fails if the files in the directory aren't the ones in the tar.
+pub fn unpack(metadata: &TarMetadata, dir: &Path) -> Result<Vec<u8>, Error> {
+    let mut out = Vec::new();
+
+    for segment in &metadata.segments {
+        match *segment {
+            TarSegment::Raw(ref bytes) => out.extend(bytes),
+            TarSegment::File { ref path, len } => {
+                let file = open(dir, path)?;
+                let before = out.len() as u64;
+                file.take(len)
+                    .read_to_end(&mut out)
+                    .with_context(|| anyhow!("reading {:?}", path))?;
+                ensure!(
+                    out.len() as u64 - before == len,
+                    "{:?} is shorter than it was in the tar",
+                    path
+                );
+            }
+        }
+    }
+
+    ensure!(
+        filter::sha512(&out) == metadata.hash,
+        "the rebuilt tar isn't the original; has the directory changed?"
+    );
+
+    Ok(out)
+}
+
+/// Pack a compressed tar, such as a `.tar.gz`, in two layers: the compression, then the tar.
+pub fn pack_compressed(data: &[u8]) -> Result<(Metadata, TarMetadata), Error> {
+    let (metadata, tar) = pack::pack(data)?;
+    Ok((metadata, pack(&tar)?))
+}
+
+/// Rebuild a compressed tar from the directory it was extracted into.
+pub fn unpack_compressed(
+    metadata: &Metadata,
+    tar_metadata: &TarMetadata,
+    dir: &Path,
+) -> Result<Vec<u8>, Error> {
+    pack::unpack(metadata, &unpack(tar_metadata, dir)?)
+}
+
+impl TarMetadata {
+    pub fn write<W: Write>(&self, mut into: W) -> Result<(), Error> {
+        into.write_all(MAGIC)?;
+        into.write_u8(VERSION)?;
+        ensure!(filter::HASH_LEN == self.hash.len(), "hash must be SHA-512");
+        into.write_all(&self.hash)?;
+        into.write_u32::<LE>(u32::try_from(self.segments.len())?)?;
+
+        for segment in &self.segments {
+            match *segment {
+                TarSegment::Raw(ref bytes) => {
+                    into.write_u8(0)?;
+                    into.write_u64::<LE>(u64::try_from(bytes.len())?)?;
+                    into.write_all(bytes)?;
+                }
+                TarSegment::File { ref path, len } => {
+                    into.write_u8(1)?;
+                    into.write_u16::<LE>(u16::try_from(path.len())?)?;
+                    into.write_all(path.as_bytes())?;
+                    into.write_u64::<LE>(len)?;
+                }
+            }
+        }
+
+        Ok(())
+    }
+
+    /// Files count against the `decompressed_len` limit, and verbatim bytes the `raw_len`;
+    /// `files_len` gives the limit for a directory.
+    pub fn read<R: Read>(mut from: R, limits: Limits) -> Result<TarMetadata, Error> {
+        let mut magic = [0u8; 10];
+        from.read_exact(&mut magic)?;
+        ensure!(MAGIC == magic, "not a rezip tar metadata file");
+
+        let version = from.read_u8()?;
+        ensure!(VERSION == version, "unsupported version: {}", version);
+
+        let mut hash = vec![0u8; filter::HASH_LEN];
+        from.read_exact(&mut hash)?;
+
+        let segment_count = from.read_u32::<LE>()?;
+        let mut segments = Vec::new();
+        let mut remaining = limits;
+
+        for _ in 0..segment_count {
+            segments.push(match from.read_u8()? {
+                0 => {
+                    let len = from.read_u64::<LE>()?;
+                    ensure!(
+                        len <= remaining.raw_len,
+                        "{} byte(s) is over the limit",
+                        len
+                    );
+                    remaining.raw_len -= len;
+
+                    let mut bytes = Vec::new();
+                    (&mut from).take(len).read_to_end(&mut bytes)?;
+                    ensure!(bytes.len() as u64 == len, "truncated raw segment");
+                    TarSegment::Raw(bytes)
+                }
+                1 => {
+                    let mut path = vec![0u8; usize::from(from.read_u16::<LE>()?)];
+                    from.read_exact(&mut path)?;
+                    let path = String::from_utf8(path)?;
+                    ensure!(is_safe(&path), "unsafe path: {:?}", path);
+
+                    let len = from.read_u64::<LE>()?;
+                    ensure!(
+                        len <= remaining.decompressed_len,
+                        "{:?} claims {} byte(s), over the limit",
+                        path,
+                        len
+                    );
+                    remaining.decompressed_len -= len;
+                    TarSegment::File { path, len }
+                }
+                other => bail!("invalid segment type: {}", other),
+            });
+        }
+
+        Ok(TarMetadata { hash, segments })
+    }
+}
+
+/// The total length of the regular files under `dir`, which is the most that the metadata of a
+/// tar extracted into it can claim to find there.
+pub fn files_len(dir: &Path) -> Result<u64, Error> {
+    let mut total = 0;
+    for entry in fs::read_dir(dir)? {
+        let entry = entry?;
+        let file_type = entry.file_type()?;
+        if file_type.is_dir() {
+            total += files_len(&entry.path())?;
+        } else if file_type.is_file() {
+            total += entry.metadata()?.len();
+        }
+    }
+    Ok(total)
+}
+
+fn push_raw(segments: &mut Vec<TarSegment>, bytes: &[u8]) {
+    if !bytes.is_empty() {
+        segments.push(TarSegment::Raw(bytes.to_vec()));
+    }
+}
+
+/// Paths which extract to the same place, like `./a` and `a`, or `a//b` and `a/b`, are equal.
+fn normalise(path: &str) -> PathBuf {
+    Path::new(path)
+        .components()
+        .filter(|c| Component::CurDir != *c)
+        .collect()
+}
+
+/// Whether `path` stays inside the directory, however it's joined on.
+fn is_safe(path: &str) -> bool {
+    let path = Path::new(path);
+    path.components().next().is_some()
+        && path
+            .components()
+            .all(|c| matches!(c, Component::Normal(_) | Component::CurDir))
+}
+
+/// Open a file from the tar, refusing to follow symlinks out of the directory.
+fn open(dir: &Path, path: &str) -> Result<fs::File, Error> {
+    let mut full = dir.to_path_buf();
+    for component in Path::new(path).components() {
+        full.push(component);
+        let metadata = fs::symlink_metadata(&full)
+            .with_context(|| anyhow!("{:?} is missing from the directory", path))?;
+        ensure!(
+            !metadata.file_type().is_symlink(),
+            "{:?} is reached through a symlink",
+            path
+        );
+    }
+
+    ensure!(fs::metadata(&full)?.is_file(), "{:?} isn't a file", path);
+    Ok(fs::File::open(&full)?)
+}
+
+#[cfg(test)]
+mod tests {
+    use std::path::PathBuf;
+    use std::process;
+
+    use super::*;
+
+    /// Removed when dropped.
+    struct TempDir(PathBuf);
+
+    impl TempDir {
+        fn new(name: &str) -> TempDir {
+            let path = std::env::temp_dir().join(format!("rezip-{}-{}", name, process::id()));
+            let _ = fs::remove_dir_all(&path);
+            fs::create_dir_all(&path).unwrap();
+            TempDir(path)
+        }
+    }
+
+    impl Drop for TempDir {
+        fn drop(&mut self) {
+            let _ = fs::remove_dir_all(&self.0);
+        }
+    }
+
+    fn extract(tar: &[u8], name: &str) -> TempDir {
+        let dir = TempDir::new(name);
+        tar::Archive::new(tar).unpack(&dir.0).unwrap();
+        dir
+    }
+
+    fn append(builder: &mut tar::Builder<Vec<u8>>, kind: EntryType, path: &str, data: &[u8]) {
+        let mut header = tar::Header::new_gnu();
+        header.set_entry_type(kind);
+        header.set_size(data.len() as u64);
+        header.set_mode(0o644);
+        builder.append_data(&mut header, path, data).unwrap();
+    }
+
+    #[test]
+    fn compressed_tars() {
+        for (name, file) in &[
+            (
+                "libcgi",
+                &include_bytes!("../tests/data/libcgi-untaint-email-perl_0.03.orig.tar.gz")[..],
+            ),
+            (
+                "librole",
+                &include_bytes!("../tests/data/librole-basic-perl_0.13-1.debian.tar.gz")[..],
+            ),
+        ] {
+            let (metadata, tar_metadata) = pack_compressed(file).unwrap();
+            let dir = extract(&pack::pack(file).unwrap().1, name);
+
+            let mut serialised = Vec::new();
+            tar_metadata.write(&mut serialised).unwrap();
+            let files_len = files_len(&dir.0).unwrap();
+            assert_eq!(
+                tar_metadata,
+                TarMetadata::read(&serialised[..], Limits::unpacking(files_len)).unwrap()
+            );
+            assert!(TarMetadata::read(&serialised[..], Limits::unpacking(files_len - 1)).is_err());
+            assert!(tar_metadata
+                .segments
+                .iter()
+                .any(|s| matches!(s, TarSegment::File { .. })));
+
+            assert_eq!(
+                *file,
+                unpack_compressed(&metadata, &tar_metadata, &dir.0)
+                    .unwrap()
+                    .as_slice()
+            );
+        }
+    }
+
+    #[test]
+    fn awkward_entries() {
+        let long = format!("top/{}/file", "long".repeat(40));
+
+        let mut builder = tar::Builder::new(Vec::new());
+        append(&mut builder, EntryType::Directory, "top/", b"");
+        append(&mut builder, EntryType::Regular, "top/a", b"replaced");
+        append(&mut builder, EntryType::Regular, &long, b"a long name");
+        append(&mut builder, EntryType::Regular, "top/empty", b"");
+        append(&mut builder, EntryType::Regular, "top/a", b"kept");
+        let mut tar = builder.into_inner().unwrap();
+        // trailing junk, after the end of the archive
+        tar.extend(b"junk");
+
+        let metadata = pack(&tar).unwrap();
+        let files: Vec<&str> = metadata
+            .segments
+            .iter()
+            .filter_map(|s| match *s {
+                TarSegment::File { ref path, .. } => Some(path.as_str()),
+                TarSegment::Raw(_) => None,
+            })
+            .collect();
+        assert_eq!(vec![long.as_str(), "top/a"], files);
+
+        let dir = extract(&tar, "awkward");
+        assert_eq!(tar, unpack(&metadata, &dir.0).unwrap());
+
+        fs::write(dir.0.join("top/a"), b"KEPT").unwrap();
+        assert!(unpack(&metadata, &dir.0).is_err());
+        fs::remove_file(dir.0.join("top/a")).unwrap();
+        assert!(unpack(&metadata, &dir.0).is_err());
+    }
+
+    #[test]
+    fn mixed_prefixes() {
+        // the builder tidies paths, so write the names as other tars might have them
+        let mut builder = tar::Builder::new(Vec::new());
+        for (path, data) in &[
+            ("./a", &b"replaced"[..]),
+            ("a", b"kept"),
+            ("b", b"replaced"),
+            ("./b", b"kept"),
+            ("c//d", b"replaced"),
+            ("c/d", b"kept"),
+        ] {
+            let mut header = tar::Header::new_ustar();
+            header.as_old_mut().name[..path.len()].copy_from_slice(path.as_bytes());
+            header.set_size(data.len() as u64);
+            header.set_mode(0o644);
+            header.set_cksum();
+            builder.append(&header, *data).unwrap();
+        }
+        let tar = builder.into_inner().unwrap();
+
+        let metadata = pack(&tar).unwrap();
+        let files = metadata
+            .segments
+            .iter()
+            .filter(|s| matches!(s, TarSegment::File { .. }))
+            .count();
+        assert_eq!(3, files);
+
+        let dir = extract(&tar, "prefixes");
+        assert_eq!(tar, unpack(&metadata, &dir.0).unwrap());
+    }
+
+    #[test]
+    fn links() {
+        let mut builder = tar::Builder::new(Vec::new());
+        append(&mut builder, EntryType::Directory, "v1/", b"");
+        append(&mut builder, EntryType::Regular, "v1/file", b"overwritten");
+        let mut header = tar::Header::new_gnu();
+        header.set_entry_type(EntryType::Symlink);
+        header.set_size(0);
+        header.set_mode(0o777);
+        builder.append_link(&mut header, "current", "v1").unwrap();
+        append(
+            &mut builder,
+            EntryType::Regular,
+            "current/file",
+            b"through the link",
+        );
+        append(&mut builder, EntryType::Regular, "other", b"unaffected");
+        let tar = builder.into_inner().unwrap();
+
+        let metadata = pack(&tar).unwrap();
+        let files: Vec<&str> = metadata
+            .segments
+            .iter()
+            .filter_map(|s| match *s {
+                TarSegment::File { ref path, .. } => Some(path.as_str()),
+                TarSegment::Raw(_) => None,
+            })
+            .collect();
+        assert_eq!(vec!["other"], files);
+
+        let dir = extract(&tar, "links");
+        assert_eq!(tar, unpack(&metadata, &dir.0).unwrap());
+    }
+
+    #[test]
+    fn unsafe_paths() {
+        assert!(is_safe("a/b"));
+        assert!(is_safe("./a"));
+        assert!(!is_safe("../a"));
+        assert!(!is_safe("a/../../b"));
+        assert!(!is_safe("/etc/passwd"));
+        assert!(!is_safe(""));
+
+        let metadata = TarMetadata {
+            hash: vec![0; filter::HASH_LEN],
+            segments: vec![TarSegment::File {
+                path: "../secret".to_string(),
+                len: 1,
+            }],
+        };
+        let mut serialised = Vec::new();
+        metadata.write(&mut serialised).unwrap();
+        assert!(TarMetadata::read(&serialised[..], Limits::default()).is_err());
+    }
+}
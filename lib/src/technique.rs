@@ -1,29 +1,123 @@
+use std::collections::btree_map::Entry;
+use std::collections::BTreeMap;
+use std::sync::Arc;
+use std::sync::RwLock;
 use std::u16;
 
+use anyhow::bail;
+use anyhow::Error;
+use lazy_static::lazy_static;
+
 use crate::all_refs::AllRefs;
+use crate::back_map::BackMap;
+use crate::chain_hash::ChainHash;
 use crate::lookahead::Lookahead;
 use crate::picker::Picker;
 use crate::wams;
 use crate::wams::WamsOptimisations;
 use crate::Code;
 use crate::DataLen;
-use crate::Guesser;
 use crate::Looker;
-use crate::Obscure;
 use crate::Ref;
 
 #[derive(Copy, Clone, Debug, Eq, PartialEq)]
 pub struct Config {
     pub first_byte_bug: bool,
+    pub hash: ChainHash,
     pub lookahead: Lookahead,
     pub picker: Picker,
     pub wams: WamsOptimisations,
 }
 
+/// A model of an encoder: something which can guess which codes the encoder would pick.
+///
+/// Implementations are constructed over some data (and the preroll before it), usually by
+/// name, through the registry; see `register`.
+pub trait Technique {
+    /// Start guessing from the start of the data.
+    fn scanner(&self) -> Box<dyn Scanner + '_>;
+
+    /// `pos` is the distance through all known data, including the preroll, like `Scanner::pos`.
+    fn byte_at(&self, pos: usize) -> u8;
+}
+
+/// Walks through the data, guessing codes, and being told which the encoder actually picked.
+pub trait Scanner {
+    /// The distance through all known data which we have processed, including the preroll.
+    fn pos(&self) -> usize;
+
+    fn more_data(&self) -> bool;
+
+    /// The codes we think the encoder would pick next, if each of them is correct; never empty.
+    fn codes(&self) -> Vec<Code>;
+
+    /// The encoder actually picked `code` next.
+    fn feedback(&mut self, code: Code);
+
+    /// Other codes the encoder could reasonably have picked next, most likely first.
+    fn alternatives(&self) -> Vec<Code>;
+}
+
+pub type Constructor =
+    Arc<dyn for<'a> Fn(&'a [u8], &'a [u8]) -> Box<dyn Technique + 'a> + Send + Sync>;
+
+lazy_static! {
+    static ref REGISTRY: RwLock<BTreeMap<String, Constructor>> = {
+        let mut registry = BTreeMap::new();
+        for level in 1..=9 {
+            registry.insert(format!("gzip-{}", level), emulating(Config::gzip(level)));
+        }
+        registry.insert("spicy".to_string(), emulating(Config::spicy()));
+        RwLock::new(registry)
+    };
+}
+
+/// Make a `Technique` available by `name`.
+///
+/// The name is stored in metadata, so must be the same when unpacking; a name which is already
+/// taken, including the builtins, is an error, as replacing it would change how existing
+/// metadata unpacks.
+pub fn register<F>(name: &str, constructor: F) -> Result<(), Error>
+where
+    F: for<'a> Fn(&'a [u8], &'a [u8]) -> Box<dyn Technique + 'a> + Send + Sync + 'static,
+{
+    match REGISTRY
+        .write()
+        .expect("not poisoned")
+        .entry(name.to_string())
+    {
+        Entry::Occupied(_) => bail!("technique {:?} is already registered", name),
+        Entry::Vacant(entry) => {
+            entry.insert(Arc::new(constructor));
+        }
+    }
+
+    Ok(())
+}
+
+pub fn lookup(name: &str) -> Option<Constructor> {
+    REGISTRY.read().expect("not poisoned").get(name).cloned()
+}
+
+/// Every registered name, in order.
+pub fn names() -> Vec<String> {
+    REGISTRY
+        .read()
+        .expect("not poisoned")
+        .keys()
+        .cloned()
+        .collect()
+}
+
+fn emulating(config: Config) -> Constructor {
+    Arc::new(move |preroll, data| Box::new(Emulator::new(config, preroll, data)))
+}
+
+/// The `Technique` for encoders which can be described by a `Config`, such as gzip.
 #[derive(Debug)]
-pub struct Technique<'a, 'p: 'a, 'd: 'a> {
+pub struct Emulator<'d> {
     config: Config,
-    all_refs: &'a AllRefs<'p, 'd>,
+    all_refs: AllRefs<'d>,
 }
 
 impl Config {
@@ -34,6 +128,7 @@ impl Config {
         );
         Config {
             first_byte_bug: true,
+            hash: ChainHash::GZIP,
             lookahead: Lookahead::Greedy,
             picker: if level >= 4 {
                 Picker::DropFarThrees
@@ -51,6 +146,7 @@ impl Config {
     pub fn spicy() -> Self {
         Config {
             first_byte_bug: false,
+            hash: ChainHash::GZIP,
             lookahead: Lookahead::ThreeZip,
             picker: Picker::DropFarThrees,
             wams: wams::CONFIGURATIONS[8],
@@ -58,68 +154,131 @@ impl Config {
     }
 }
 
-impl<'a, 'p, 'd> Technique<'a, 'p, 'd> {
-    pub fn new(config: Config, all_refs: &'a AllRefs<'p, 'd>) -> Self {
-        Technique { config, all_refs }
-    }
-
-    pub fn byte_at(&self, pos: usize) -> u8 {
-        self.all_refs.data[pos]
+impl<'d> Emulator<'d> {
+    pub fn new(config: Config, preroll: &[u8], data: &'d [u8]) -> Self {
+        Emulator {
+            config,
+            all_refs: AllRefs::with_sixteen(preroll, data),
+        }
     }
 }
 
-impl<'a, 'p, 'd> Technique<'a, 'p, 'd> {
-    pub fn scanner(&self) -> Scanner {
-        Scanner {
+impl<'d> Technique for Emulator<'d> {
+    fn scanner(&self) -> Box<dyn Scanner + '_> {
+        let mut scanner = EmulatorScanner {
             technique: self,
-            obscured: Vec::new(),
-            pos: self.all_refs.preroll.len(),
+            chains: BackMap::new(self.config.hash, self.all_refs.data_len()),
+            pos: self.all_refs.preroll_len(),
+        };
+
+        // we don't know how the encoder processed the preroll, so assume it inserted everything
+        for pos in 0..scanner.pos {
+            scanner.insert(pos);
         }
+
+        Box::new(scanner)
+    }
+
+    fn byte_at(&self, pos: usize) -> u8 {
+        self.all_refs.get(pos)
     }
 }
 
 #[derive(Debug)]
-pub struct Scanner<'t, 'a: 't, 'p: 'a + 't, 'd: 'a + 't> {
-    technique: &'t Technique<'a, 'p, 'd>,
-    obscured: Vec<Obscure>,
-    /// The distance through all known data which we have processed, including the preroll.
-    pub pos: usize,
+pub struct EmulatorScanner<'t, 'd: 't> {
+    technique: &'t Emulator<'d>,
+    /// Every position before `pos` the encoder would have inserted.
+    chains: BackMap,
+    pos: usize,
 }
 
-impl<'t, 'a, 'p, 'd> Scanner<'t, 'a, 'p, 'd> {
-    pub fn more_data(&self) -> bool {
+impl<'t, 'd> EmulatorScanner<'t, 'd> {
+    fn insert(&mut self, pos: usize) {
+        if 0 == pos && self.technique.config.first_byte_bug {
+            // zlib and gzip use zero to mean "no position", so never find a match there
+            return;
+        }
+
+        if let Some(hash) = self.technique.all_refs.hash(pos, self.chains.hash()) {
+            self.chains.insert(pos, hash);
+        }
+    }
+}
+
+impl<'t, 'd> Scanner for EmulatorScanner<'t, 'd> {
+    fn pos(&self) -> usize {
+        self.pos
+    }
+
+    fn more_data(&self) -> bool {
         self.pos < self.data_len()
     }
 
-    pub fn feedback(&mut self, code: Code) {
+    fn codes(&self) -> Vec<Code> {
+        self.technique.config.lookahead.lookahead(self, self.pos)
+    }
+
+    fn feedback(&mut self, code: Code) {
         let old_pos = self.pos;
         self.pos += usize::from(code.emitted_bytes());
 
-        let limit = match self.technique.config.wams.insert_only_below_length {
-            Some(limit) => limit,
-            None => return,
-        };
-
-        let r = match code {
-            Code::Reference(r) => r,
-            Code::Literal(_) => return,
+        let skip_inside = match (code, self.technique.config.wams.insert_only_below_length) {
+            (Code::Reference(r), Some(limit)) => r.run() > limit,
+            _ => false,
         };
 
-        if r.run() <= limit {
+        if skip_inside {
+            // only the start of a long match is inserted
+            self.insert(old_pos);
             return;
         }
 
-        self.obscured.push((old_pos, r.run()))
+        for pos in old_pos..self.pos {
+            self.insert(pos);
+        }
+    }
+
+    /// Our guess, the literal, then every other reference we know of, longest first.
+    fn alternatives(&self) -> Vec<Code> {
+        let mut ret = Vec::new();
+        let literal = Code::Literal(self.technique.all_refs.get(self.pos));
+
+        if let Some(&guess) = self.codes().first() {
+            ret.push(guess);
+        }
+
+        if !ret.contains(&literal) {
+            ret.push(literal);
+        }
+
+        let mut refs: Vec<Ref> =
+            match self
+                .technique
+                .all_refs
+                .at(self.pos, &self.chains, self.pos..self.pos, usize::MAX)
+            {
+                Some(it) => it.collect(),
+                None => Vec::new(),
+            };
+        refs.sort_by_key(|r| (u16::MAX - r.run(), r.dist));
+
+        for r in refs {
+            if !ret.contains(&Code::Reference(r)) {
+                ret.push(Code::Reference(r));
+            }
+        }
+
+        ret
     }
 }
 
-impl<'t, 'a, 'p, 'd, 'o> DataLen for Scanner<'t, 'a, 'p, 'd> {
+impl<'t, 'd> DataLen for EmulatorScanner<'t, 'd> {
     fn data_len(&self) -> usize {
         self.technique.all_refs.data_len()
     }
 }
 
-impl<'t, 'a, 'p, 'd, 'o> Looker for Scanner<'t, 'a, 'p, 'd> {
+impl<'t, 'd> Looker for EmulatorScanner<'t, 'd> {
     fn best_candidate_better_than(&self, pos: usize, other: Option<u16>) -> (u8, Option<Ref>) {
         let current_literal = self.technique.all_refs.get(pos);
         let mut limit = self.technique.config.wams.limit_count_of_distances;
@@ -136,21 +295,151 @@ impl<'t, 'a, 'p, 'd, 'o> Looker for Scanner<'t, 'a, 'p, 'd> {
             }
         }
 
-        let candidates = self.technique.all_refs.at(pos, &self.obscured);
+        // positions we're looking ahead over would have been inserted before the search
+        let candidates = self
+            .technique
+            .all_refs
+            .at(pos, &self.chains, self.pos..pos, limit);
         (
             current_literal,
             candidates.and_then(|it| {
-                self.technique.config.picker.picker(
-                    it.take(limit),
-                    self.technique.config.wams.quit_search_above_length,
-                )
+                self.technique
+                    .config
+                    .picker
+                    .picker(it, self.technique.config.wams.quit_search_above_length)
             }),
         )
     }
 }
 
-impl<'t, 'a, 'p, 'd> Guesser for Scanner<'t, 'a, 'p, 'd> {
-    fn codes(&self) -> Vec<Code> {
-        self.technique.config.lookahead.lookahead(self, self.pos)
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::compress;
+    use crate::test_data;
+    use crate::trace;
+    use crate::Trace;
+
+    /// Always guesses a literal, so traces every reference as a miss.
+    struct Literals<'a>(&'a [u8]);
+
+    struct LiteralsScanner<'t>(&'t [u8], usize);
+
+    impl<'a> Technique for Literals<'a> {
+        fn scanner(&self) -> Box<dyn Scanner + '_> {
+            Box::new(LiteralsScanner(self.0, 0))
+        }
+
+        fn byte_at(&self, pos: usize) -> u8 {
+            self.0[pos]
+        }
+    }
+
+    impl<'t> Scanner for LiteralsScanner<'t> {
+        fn pos(&self) -> usize {
+            self.1
+        }
+
+        fn more_data(&self) -> bool {
+            self.1 < self.0.len()
+        }
+
+        fn codes(&self) -> Vec<Code> {
+            vec![Code::Literal(self.0[self.1])]
+        }
+
+        fn feedback(&mut self, code: Code) {
+            self.1 += usize::from(code.emitted_bytes());
+        }
+
+        fn alternatives(&self) -> Vec<Code> {
+            self.codes()
+        }
+    }
+
+    #[test]
+    fn builtins() {
+        let names = names();
+        for level in 1..=9 {
+            assert!(names.contains(&format!("gzip-{}", level)));
+        }
+        assert!(names.contains(&"spicy".to_string()));
+        assert!(lookup("gzip-0").is_none());
+    }
+
+    #[test]
+    fn custom() {
+        // the registry is shared by every test in the process, so the name is unique to this one
+        register("technique-tests-literals", |_preroll, data| {
+            Box::new(Literals(data))
+        })
+        .unwrap();
+        let constructor = lookup("technique-tests-literals").unwrap();
+
+        assert!(register("technique-tests-literals", |_preroll, data| {
+            Box::new(Literals(data))
+        })
+        .is_err());
+        assert!(register("gzip-6", |_preroll, data| Box::new(Literals(data))).is_err());
+
+        let data = b"abcabc";
+        let codes = [
+            Code::Literal(b'a'),
+            Code::Literal(b'b'),
+            Code::Literal(b'c'),
+            Code::Reference(Ref::new(3, 3)),
+        ];
+
+        let trace = trace::validate(&codes, &*constructor(&[], data));
+        assert!(trace[..3].iter().all(|t| Trace::Correct == *t));
+        assert!(Trace::Actually(Ref::new(3, 3)) == trace[3]);
+    }
+
+    #[test]
+    fn alternatives_start_with_the_guess() {
+        let data = b"abcdabcdabcd";
+        let technique = Emulator::new(Config::gzip(6), &[], data);
+        let mut scanner = technique.scanner();
+        while scanner.more_data() {
+            let guess = scanner.codes()[0];
+            let alternatives = scanner.alternatives();
+            assert_eq!(guess, alternatives[0]);
+            assert!(alternatives.contains(&Code::Literal(technique.byte_at(scanner.pos()))));
+            scanner.feedback(guess);
+        }
+    }
+
+    #[test]
+    fn hashes() {
+        // few enough distinct bytes that most chains are full, so collisions change the search
+        let data: Vec<u8> = test_data::noise()
+            .take(20_000)
+            .map(|n| b'a' + (n % 8) as u8)
+            .collect();
+
+        let gzip = Config::gzip(4);
+        let hashes = [
+            ChainHash::zlib(1),
+            ChainHash::zlib(9),
+            ChainHash::ZLIB_NG,
+            ChainHash::LIBDEFLATE,
+            ChainHash::Crc32c { bits: 12 },
+        ];
+
+        for hash in hashes {
+            let config = Config { hash, ..gzip };
+            let codes = compress::codes(&data, config);
+            let trace = trace::validate(&codes, &Emulator::new(config, &[], &data));
+            assert!(trace.iter().all(|t| Trace::Correct == *t), "{:?}", hash);
+        }
+
+        let codes = compress::codes(
+            &data,
+            Config {
+                hash: ChainHash::zlib(1),
+                ..gzip
+            },
+        );
+        assert_ne!(codes, compress::codes(&data, gzip));
     }
 }
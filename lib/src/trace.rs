@@ -1,11 +1,14 @@
 use std::iter;
 
+use anyhow::anyhow;
+use anyhow::Error;
+
+use crate::technique::Scanner;
 use crate::technique::Technique;
 use crate::Code;
-use crate::Guesser;
 use crate::Trace;
 
-pub fn trace(codes: &[Code], technique: &Technique) -> Vec<Trace> {
+pub fn trace(codes: &[Code], technique: &dyn Technique) -> Vec<Trace> {
     let mut ret = Vec::with_capacity(codes.len());
 
     let mut codes = codes.into_iter().peekable();
@@ -33,14 +36,16 @@ pub fn trace(codes: &[Code], technique: &Technique) -> Vec<Trace> {
                 });
                 scanner.feedback(code);
             }
-            None => panic!("the guesser guessed more than there actually are?"),
+            // fewer codes than data; the trace just covers the codes there are
+            None => break,
         }
     }
 
     ret
 }
 
-pub fn restore(trace: &[Trace], technique: &Technique) -> Vec<Code> {
+/// Recover the codes from a trace produced by `trace`, failing if it runs out early.
+pub fn restore(trace: &[Trace], technique: &dyn Technique) -> Result<Vec<Code>, Error> {
     let mut ret = Vec::with_capacity(trace.len());
 
     let mut trace = trace.into_iter().peekable();
@@ -51,11 +56,13 @@ pub fn restore(trace: &[Trace], technique: &Technique) -> Vec<Code> {
         assert!(!guesses.is_empty());
 
         for guess in guesses {
-            let hint = *trace.next().expect("not out of data");
+            let hint = *trace
+                .next()
+                .ok_or_else(|| anyhow!("trace ended before the data"))?;
             let orig = match hint {
                 Trace::Correct => guess,
                 Trace::Actually(r) => Code::Reference(r),
-                Trace::ActuallyLiteral => Code::Literal(technique.byte_at(scanner.pos)),
+                Trace::ActuallyLiteral => Code::Literal(technique.byte_at(scanner.pos())),
             };
 
             scanner.feedback(orig);
@@ -71,12 +78,134 @@ pub fn restore(trace: &[Trace], technique: &Technique) -> Vec<Code> {
         }
     }
 
+    Ok(ret)
+}
+
+/// Like `trace`, but first bring the technique up to date by feeding it `replayed`, the codes
+/// which its data starts with, then stop when the `codes` run out, rather than the data.
+///
+/// The `replayed` codes are only fed back, not searched for, so replaying is cheap.
+pub fn trace_after(replayed: &[Code], codes: &[Code], technique: &dyn Technique) -> Vec<Trace> {
+    trace_after_with(replayed, codes, technique, |_, _| {})
+}
+
+/// `trace_after`, calling `miss` with the remaining guesses, and the actual code, at each miss.
+pub fn trace_after_with<F: FnMut(&[Code], Code)>(
+    replayed: &[Code],
+    codes: &[Code],
+    technique: &dyn Technique,
+    mut miss: F,
+) -> Vec<Trace> {
+    let mut ret = Vec::with_capacity(codes.len());
+
+    let mut codes = codes.iter().peekable();
+    let mut scanner = technique.scanner();
+    replay(&mut *scanner, replayed);
+
+    while scanner.more_data() && codes.peek().is_some() {
+        let guesses = scanner.codes();
+        assert!(!guesses.is_empty());
+
+        let matches = shared_prefix(&guesses, &mut codes);
+        for matched in matches {
+            ret.push(Trace::Correct);
+            scanner.feedback(*matched);
+        }
+
+        if matches.len() == guesses.len() {
+            continue;
+        }
+
+        match codes.next() {
+            Some(&code) => {
+                miss(&guesses[matches.len()..], code);
+                ret.push(match code {
+                    Code::Literal(_) => Trace::ActuallyLiteral,
+                    Code::Reference(r) => Trace::Actually(r),
+                });
+                scanner.feedback(code);
+            }
+            None => break,
+        }
+    }
+
     ret
 }
 
-pub fn validate(codes: &[Code], technique: &Technique) -> Vec<Trace> {
+/// Recover the codes from a trace produced by `trace_after`, with the same `replayed` codes.
+pub fn restore_after(replayed: &[Code], trace: &[Trace], technique: &dyn Technique) -> Vec<Code> {
+    let mut ret = Vec::with_capacity(trace.len());
+
+    let mut trace = trace.iter();
+    let mut scanner = technique.scanner();
+    replay(&mut *scanner, replayed);
+
+    'groups: while scanner.more_data() {
+        for guess in scanner.codes() {
+            let orig = match trace.next() {
+                Some(Trace::Correct) => guess,
+                Some(&Trace::Actually(r)) => Code::Reference(r),
+                Some(Trace::ActuallyLiteral) => Code::Literal(technique.byte_at(scanner.pos())),
+                None => break 'groups,
+            };
+
+            scanner.feedback(orig);
+            ret.push(orig);
+
+            if orig != guess {
+                // the guesser was wrong, and we moved in a way it doesn't understand; ignore it
+                break;
+            }
+        }
+    }
+
+    ret
+}
+
+/// Feed the technique `replayed`, like `trace_after`, then follow its own guesses for `len`
+/// bytes, with literals wherever a guess would run further.
+pub fn guess_after(replayed: &[Code], len: usize, technique: &dyn Technique) -> Vec<Code> {
+    let mut ret = Vec::new();
+
+    let mut scanner = technique.scanner();
+    replay(&mut *scanner, replayed);
+    let end = scanner.pos() + len;
+
+    'groups: while scanner.more_data() {
+        for guess in scanner.codes() {
+            if scanner.pos() >= end {
+                break 'groups;
+            }
+
+            let code = if scanner.pos() + usize::from(guess.emitted_bytes()) > end {
+                Code::Literal(technique.byte_at(scanner.pos()))
+            } else {
+                guess
+            };
+
+            scanner.feedback(code);
+            ret.push(code);
+
+            if code != guess {
+                break;
+            }
+        }
+    }
+
+    ret
+}
+
+/// Bring the scanner up to date with codes which are already known; their guesses don't matter,
+/// only what the scanner remembers about them, like its hash chains.
+fn replay(scanner: &mut dyn Scanner, replayed: &[Code]) {
+    for &code in replayed {
+        scanner.feedback(code);
+    }
+}
+
+pub fn validate(codes: &[Code], technique: &dyn Technique) -> Vec<Trace> {
     let trace = trace(codes, technique);
-    let restored = restore(&trace, technique);
+    let restored = restore(&trace, technique).expect("restoring our own trace");
 
     assert_eq!(codes, restored.as_slice());
 
@@ -120,4 +249,21 @@ mod tests {
 
         assert!(shared_prefix(&[1, 5, 7], &mut iter::empty().peekable()).is_empty());
     }
+
+    #[test]
+    fn too_few_codes() {
+        use super::trace;
+        use crate::technique::Config;
+        use crate::technique::Emulator;
+        use crate::Code;
+        use crate::Trace;
+
+        let data = b"hello, hello, hello";
+        let technique = Emulator::new(Config::gzip(6), &[], data);
+        assert!(trace(&[], &technique).is_empty());
+        assert_eq!(
+            vec![Trace::ActuallyLiteral],
+            trace(&[Code::Literal(b'x')], &technique)
+        );
+    }
 }
@@ -1,26 +1,359 @@
-use std::u16;
+use anyhow::ensure;
+use anyhow::Error;
 
-use crate::all_refs::AllRefs;
 use crate::serialise_trace;
 use crate::technique::Config;
+use crate::technique::Constructor;
+use crate::technique::Emulator;
 use crate::technique::Technique;
 use crate::trace;
 use crate::Code;
 use crate::Trace;
 
+/// The most history a `DEFLATE` reference can reach back into.
+pub const WINDOW_SIZE: usize = 32 * 1024;
+
+/// The most data after a block that an encoder could have looked at while encoding it;
+/// zlib's `MIN_LOOKAHEAD`.
+pub const LOOKAHEAD: usize = 258 + 3 + 1;
+
+/// Traces, or restores, a stream's blocks in order, carrying the encoder's state between them.
+///
+/// Before each block, the technique is fed the codes from the last window again, so it is in the
+/// state it would have been in if it had seen the whole stream: guesses can run across block
+/// boundaries, and anything it remembers about earlier codes, like gaps in its hash chains,
+/// is kept.
+///
+/// Owns everything it needs, so can be kept in long-lived structs, or sent between threads.
+#[derive(Clone)]
+pub struct Tracer {
+    constructor: Constructor,
+    /// The data the `replay` covers, and a window before that, for the `replay` to refer into.
+    history: Vec<u8>,
+    /// The most recent codes, covering at least a window, where there is that much.
+    replay: Vec<Code>,
+    /// The number of bytes at the end of the `history` that the `replay` covers.
+    replay_len: usize,
+}
+
+impl Tracer {
+    /// Start at the beginning of a stream, with no history.
+    pub fn new(constructor: Constructor) -> Self {
+        Tracer::with_history(constructor, Vec::new())
+    }
+
+    /// Start part-way through a stream, or with a preset dictionary.
+    ///
+    /// We don't know the codes for the `history`, so the technique will start afresh.
+    pub fn with_history(constructor: Constructor, mut history: Vec<u8>) -> Self {
+        if history.len() > WINDOW_SIZE {
+            history.drain(..history.len() - WINDOW_SIZE);
+        }
+
+        Tracer {
+            constructor,
+            history,
+            replay: Vec::new(),
+            replay_len: 0,
+        }
+    }
+
+    /// The data the next block can refer back into.
+    pub fn history(&self) -> &[u8] {
+        &self.history[self.history.len().saturating_sub(WINDOW_SIZE)..]
+    }
+
+    /// Trace the next block, whose decompressed data is `data`.
+    ///
+    /// `following` is the data after the block, which the encoder may have looked ahead into;
+    /// anything past `LOOKAHEAD` is ignored. The same must be passed to `restore_block`.
+    pub fn trace_block(&mut self, data: &[u8], following: &[u8], codes: &[Code]) -> Vec<Trace> {
+        self.trace_block_with(data, following, codes, |_, _| {})
+    }
+
+    /// `trace_block`, calling `miss` with the guesses, and the actual code, at each miss.
+    pub fn trace_block_with<F: FnMut(&[Code], Code)>(
+        &mut self,
+        data: &[u8],
+        following: &[u8],
+        codes: &[Code],
+        miss: F,
+    ) -> Vec<Trace> {
+        let trace = self.with_technique(data, following, |replay, technique| {
+            trace::trace_after_with(replay, codes, technique, miss)
+        });
+        self.advance(data, codes);
+        trace
+    }
+
+    /// Recover the codes of the next block, from a trace produced by `trace_block`.
+    ///
+    /// Fails if the codes don't cover exactly the `data`, or refer back before the history, as
+    /// they can if the trace is corrupt.
+    pub fn restore_block(
+        &mut self,
+        data: &[u8],
+        following: &[u8],
+        trace: &[Trace],
+    ) -> Result<Vec<Code>, Error> {
+        let codes = self.with_technique(data, following, |replay, technique| {
+            trace::restore_after(replay, trace, technique)
+        });
+
+        let mut len = 0;
+        for code in &codes {
+            if let Code::Reference(r) = *code {
+                ensure!(
+                    usize::from(r.dist) <= self.history.len() + len,
+                    "reference to before the data: {:?}",
+                    code
+                );
+            }
+            len += usize::from(code.emitted_bytes());
+        }
+        ensure!(
+            len == data.len(),
+            "trace restored {} byte(s) for a block of {}",
+            len,
+            data.len()
+        );
+
+        self.advance(data, &codes);
+        Ok(codes)
+    }
+
+    /// Move past a block whose codes are already known, without tracing it.
+    ///
+    /// The state is the same as after `trace_block`, but much cheaper to get to.
+    pub fn follow_block(&mut self, data: &[u8], codes: &[Code]) {
+        self.advance(data, codes);
+    }
+
+    /// Move past a block which isn't traced, such as a stored block.
+    ///
+    /// The encoder still made choices for it, which we assume were our guesses.
+    pub fn skip_block(&mut self, data: &[u8], following: &[u8]) {
+        if data.is_empty() {
+            // such as a flush; nothing for the encoder to do
+            return;
+        }
+
+        let codes = self.guess_block(data, following);
+        self.advance(data, &codes);
+    }
+
+    /// The codes the technique would pick for the next block, following all of its own
+    /// guesses, without moving past it.
+    pub fn guess_block(&mut self, data: &[u8], following: &[u8]) -> Vec<Code> {
+        self.with_technique(data, following, |replay, technique| {
+            trace::guess_after(replay, data.len(), technique)
+        })
+    }
+
+    /// Call `work` with the replay, and the technique over the replayed data, the block, and
+    /// what follows, with the rest of the history as its preroll.
+    ///
+    /// The block is put on the end of the history for the call, rather than copying the history.
+    fn with_technique<T, F: FnOnce(&[Code], &dyn Technique) -> T>(
+        &mut self,
+        data: &[u8],
+        following: &[u8],
+        work: F,
+    ) -> T {
+        let len = self.history.len();
+        self.history.extend_from_slice(data);
+        self.history
+            .extend_from_slice(&following[..following.len().min(LOOKAHEAD)]);
+
+        let (preroll, known) = self.history.split_at(len - self.replay_len);
+        let ret = work(&self.replay, &*(self.constructor)(preroll, known));
+
+        self.history.truncate(len);
+        ret
+    }
+
+    fn advance(&mut self, data: &[u8], codes: &[Code]) {
+        self.history.extend_from_slice(data);
+        self.replay.extend_from_slice(codes);
+        self.replay_len += data.len();
+
+        let mut drop = 0;
+        for code in &self.replay {
+            let len = usize::from(code.emitted_bytes());
+            if self.replay_len - len < WINDOW_SIZE {
+                break;
+            }
+            self.replay_len -= len;
+            drop += 1;
+        }
+        self.replay.drain(..drop);
+
+        let keep = self.replay_len + WINDOW_SIZE;
+        if self.history.len() > keep {
+            self.history.drain(..self.history.len() - keep);
+        }
+    }
+}
+
 pub fn try_gzip(level: u8, preroll: &[u8], data: &[u8], codes: &[Code]) -> Vec<Trace> {
     r#try(Config::gzip(level), preroll, data, codes)
 }
 
-fn r#try(config: Config, preroll: &[u8], data: &[u8], codes: &[Code]) -> Vec<Trace> {
-    let limit = config.wams.insert_only_below_length.unwrap_or(u16::MAX);
-    let all_refs = AllRefs::with_sixteen(preroll, data, limit);
+/// Like `try_gzip`, but without checking that the trace can be restored.
+pub fn trace_gzip(level: u8, preroll: &[u8], data: &[u8], codes: &[Code]) -> Vec<Trace> {
+    trace::trace(codes, &Emulator::new(Config::gzip(level), preroll, data))
+}
+
+/// Recover the codes from a trace produced by `trace_gzip`.
+pub fn restore_gzip(
+    level: u8,
+    preroll: &[u8],
+    data: &[u8],
+    trace: &[Trace],
+) -> Result<Vec<Code>, Error> {
+    trace::restore(trace, &Emulator::new(Config::gzip(level), preroll, data))
+}
+
+/// Every code the `technique` would pick, following all of its own guesses.
+pub fn guess(technique: &dyn Technique) -> Vec<Code> {
+    let mut scanner = technique.scanner();
+    let mut ret = Vec::new();
 
+    while scanner.more_data() {
+        for guess in scanner.codes() {
+            scanner.feedback(guess);
+            ret.push(guess);
+        }
+    }
+
+    ret
+}
+
+fn r#try(config: Config, preroll: &[u8], data: &[u8], codes: &[Code]) -> Vec<Trace> {
     if config.first_byte_bug {
         // TODO: ???
     }
 
-    let traces = trace::validate(codes, &Technique::new(config, &all_refs));
+    let traces = trace::validate(codes, &Emulator::new(config, preroll, data));
     serialise_trace::verify(&traces);
     traces
 }
+
+#[cfg(test)]
+mod tests {
+    use std::ops::Range;
+    use std::thread;
+
+    use super::*;
+    use crate::compress;
+    use crate::parse;
+    use crate::technique;
+    use crate::test_data;
+    use crate::Block;
+
+    #[test]
+    fn owned() {
+        fn assert_send_sync<T: Send + Sync + 'static>() {}
+        assert_send_sync::<Tracer>();
+    }
+
+    /// Where each block's decompressed data is, and its codes, if it's a huffman block.
+    fn blocks(data: &[u8], level: u8) -> Vec<(Range<usize>, Option<Vec<Code>>)> {
+        let compressed = compress::compress(data, Config::gzip(level));
+        let mut pos = 0;
+        parse::parse_deflate(&compressed[..])
+            .map(|block| {
+                let (len, codes) = match block.unwrap() {
+                    Block::Uncompressed(data) => (data.len(), None),
+                    Block::FixedHuffman(codes) | Block::DynamicHuffman { codes, .. } => (
+                        codes.iter().map(|c| usize::from(c.emitted_bytes())).sum(),
+                        Some(codes),
+                    ),
+                };
+                pos += len;
+                (pos - len..pos, codes)
+            })
+            .collect()
+    }
+
+    /// Enough codes to need several blocks, with short references between them.
+    fn noise() -> Vec<u8> {
+        test_data::noise()
+            .take(50_000)
+            .map(|n| b'0' + (n % 64) as u8)
+            .collect()
+    }
+
+    fn trace_all(
+        tracer: &mut Tracer,
+        data: &[u8],
+        blocks: &[(Range<usize>, Option<Vec<Code>>)],
+    ) -> Vec<Option<Vec<Trace>>> {
+        blocks
+            .iter()
+            .map(|(range, codes)| {
+                let (block, following) = (&data[range.clone()], &data[range.end..]);
+                match *codes {
+                    Some(ref codes) => Some(tracer.trace_block(block, following, codes)),
+                    None => {
+                        tracer.skip_block(block, following);
+                        None
+                    }
+                }
+            })
+            .collect()
+    }
+
+    #[test]
+    fn across_blocks() {
+        let data = noise();
+        let blocks = blocks(&data, 1);
+        assert!(blocks.len() > 2, "{}", blocks.len());
+
+        let mut tracer = Tracer::new(technique::lookup("gzip-1").unwrap());
+        let traces = trace_all(&mut tracer, &data, &blocks);
+        assert_eq!(&data[data.len() - WINDOW_SIZE..], tracer.history());
+
+        // restore somewhere else, to show it needn't borrow anything
+        let mut tracer = Tracer::new(technique::lookup("gzip-1").unwrap());
+        let ranges: Vec<Range<usize>> = blocks.iter().map(|(range, _)| range.clone()).collect();
+        let restored = thread::spawn(move || {
+            ranges
+                .into_iter()
+                .zip(traces)
+                .map(|(range, trace)| {
+                    let (block, following) = (&data[range.clone()], &data[range.end..]);
+                    match trace {
+                        Some(trace) => {
+                            Some(tracer.restore_block(block, following, &trace).unwrap())
+                        }
+                        None => {
+                            tracer.skip_block(block, following);
+                            None
+                        }
+                    }
+                })
+                .collect::<Vec<_>>()
+        })
+        .join()
+        .unwrap();
+
+        for ((_, codes), restored) in blocks.iter().zip(restored) {
+            assert_eq!(*codes, restored);
+        }
+    }
+
+    #[test]
+    fn state_persists() {
+        // our compressor picks its codes for the whole stream, then splits them into blocks,
+        // so tracing it with the same technique should never miss, even at block boundaries
+        let data = noise();
+        let blocks = blocks(&data, 6);
+        assert!(blocks.len() > 2, "{}", blocks.len());
+
+        let mut tracer = Tracer::new(technique::lookup("gzip-6").unwrap());
+        for trace in trace_all(&mut tracer, &data, &blocks).into_iter().flatten() {
+            assert!(trace.iter().all(|t| Trace::Correct == *t));
+        }
+    }
+}
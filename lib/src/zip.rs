@@ -0,0 +1,74 @@
+use std::convert::TryFrom;
+
+use byteorder::ByteOrder;
+use byteorder::LittleEndian as LE;
+
+pub const LOCAL_HEADER_MAGIC: &[u8] = b"PK\x03\x04";
+const DATA_DESCRIPTOR_MAGIC: &[u8] = b"PK\x07\x08";
+
+const LOCAL_HEADER_LEN: usize = 30;
+
+pub const METHOD_DEFLATE: u16 = 8;
+
+/// The parts of a local file header we need to find the end of the entry.
+#[derive(Debug)]
+pub struct LocalHeader {
+    /// The length of the header, including the file name and extra field.
+    pub len: usize,
+    pub flags: u16,
+    pub method: u16,
+    /// Zero (or garbage) if `has_data_descriptor`.
+    pub compressed_size: u32,
+}
+
+impl LocalHeader {
+    pub fn has_data_descriptor(&self) -> bool {
+        0 != (self.flags & (1 << 3))
+    }
+}
+
+/// None if there isn't a complete local file header at the start of `data`.
+pub fn local_header(data: &[u8]) -> Option<LocalHeader> {
+    if data.len() < LOCAL_HEADER_LEN || !data.starts_with(LOCAL_HEADER_MAGIC) {
+        return None;
+    }
+
+    let name_len = usize::from(LE::read_u16(&data[26..]));
+    let extra_len = usize::from(LE::read_u16(&data[28..]));
+    let len = LOCAL_HEADER_LEN + name_len + extra_len;
+
+    if data.len() < len {
+        return None;
+    }
+
+    Some(LocalHeader {
+        len,
+        flags: LE::read_u16(&data[6..]),
+        method: LE::read_u16(&data[8..]),
+        compressed_size: LE::read_u32(&data[18..]),
+    })
+}
+
+/// The length of the data descriptor at the start of `data`; the signature is optional.
+///
+/// Zip64 descriptors are not supported.
+pub fn data_descriptor_len(data: &[u8]) -> usize {
+    let len = if data.starts_with(DATA_DESCRIPTOR_MAGIC) {
+        16
+    } else {
+        12
+    };
+
+    len.min(data.len())
+}
+
+/// The end of the stored data for an entry, if it can be worked out from the header.
+pub fn stored_end(header: &LocalHeader, data_start: usize) -> Option<usize> {
+    if header.has_data_descriptor() {
+        return None;
+    }
+
+    usize::try_from(header.compressed_size)
+        .ok()
+        .and_then(|size| data_start.checked_add(size))
+}
@@ -0,0 +1,76 @@
+//! Generated data, shared between the unit tests, the integration tests and the benchmarks,
+//! none of which use all of it.
+#![allow(dead_code)]
+
+/// Where `noise` and `words` start.
+pub const SEED: u64 = 0x2545_f491_4f6c_dd1d;
+
+/// The next number from a xorshift generator: not much of a random number generator, but
+/// the same everywhere, and plenty for making test data.
+pub fn xorshift(state: &mut u64) -> u64 {
+    *state ^= *state << 13;
+    *state ^= *state >> 7;
+    *state ^= *state << 17;
+    *state
+}
+
+/// Endless numbers from `xorshift`, starting at the `SEED`.
+pub fn noise() -> impl Iterator<Item = u64> {
+    let mut state = SEED;
+    std::iter::repeat_with(move || xorshift(&mut state))
+}
+
+/// Something like text: words from a small vocabulary, with a long tail.
+pub fn words(len: usize) -> Vec<u8> {
+    const VOCABULARY: &[&str] = &[
+        "the",
+        "of",
+        "and",
+        "to",
+        "in",
+        "a",
+        "is",
+        "that",
+        "for",
+        "it",
+        "as",
+        "was",
+        "with",
+        "be",
+        "by",
+        "on",
+        "not",
+        "he",
+        "this",
+        "are",
+        "or",
+        "his",
+        "from",
+        "at",
+        "which",
+        "compress",
+        "window",
+        "huffman",
+        "reference",
+        "literal",
+        "block",
+        "stream",
+    ];
+
+    let mut state = SEED;
+    let mut ret = Vec::with_capacity(len + 16);
+    while ret.len() < len {
+        let pick = xorshift(&mut state);
+        if pick.is_multiple_of(7) {
+            // something rare, which won't match much
+            ret.extend(format!("w{}", pick % 100_000).as_bytes());
+        } else {
+            let skewed = (pick % 1024) * (pick % 1024) / 1024;
+            let word = VOCABULARY[skewed as usize * VOCABULARY.len() / 1024];
+            ret.extend(word.as_bytes());
+        }
+        ret.push(if pick.is_multiple_of(13) { b'\n' } else { b' ' });
+    }
+    ret.truncate(len);
+    ret
+}
@@ -0,0 +1,182 @@
+//! Tracks how much trace every corpus file needs, under every technique, so a change to the
+//! emulation shows its net effect, and can't quietly make some files worse.
+//!
+//! The sizes are recorded in `golden-sizes.txt`; to accept new sizes, rewrite it with:
+//!
+//! ```text
+//! REZIP_UPDATE_GOLDEN=1 cargo test -p librezip --test golden
+//! ```
+
+extern crate librezip;
+
+use std::collections::BTreeMap;
+use std::env;
+use std::fmt::Write as _;
+use std::fs;
+use std::io;
+use std::path::Path;
+use std::path::PathBuf;
+
+use librezip::serialise_trace;
+use librezip::technique;
+use librezip::Block;
+use librezip::CircularBuffer;
+use librezip::Tracer;
+
+/// How much bigger than recorded a trace may get, as a percentage, before it's a regression.
+const TOLERANCE_PERCENT: usize = 2;
+
+/// .. and in bytes, so tiny traces can wobble.
+const TOLERANCE_BYTES: usize = 2;
+
+const UPDATE_VAR: &str = "REZIP_UPDATE_GOLDEN";
+
+fn manifest_path() -> PathBuf {
+    Path::new(env!("CARGO_MANIFEST_DIR")).join("tests/golden-sizes.txt")
+}
+
+/// Every file in the corpus, by name.
+fn corpus() -> BTreeMap<String, Vec<u8>> {
+    let dir = Path::new(env!("CARGO_MANIFEST_DIR")).join("tests/data");
+    fs::read_dir(dir)
+        .unwrap()
+        .map(|entry| {
+            let path = entry.unwrap().path();
+            let name = path.file_name().unwrap().to_string_lossy().to_string();
+            (name, fs::read(&path).unwrap())
+        })
+        .collect()
+}
+
+/// The blocks in a gzip file, and its decompressed data.
+fn decode(file: &[u8]) -> (Vec<(Block, usize)>, Vec<u8>) {
+    let mut reader = io::Cursor::new(file);
+    librezip::gzip::discard_header(&mut reader).unwrap();
+
+    let mut dictionary = CircularBuffer::new();
+    let mut blocks = Vec::new();
+    let mut data = Vec::new();
+
+    for block in librezip::parse_deflate(&mut reader) {
+        let block = block.unwrap();
+        let start = data.len();
+        librezip::decompressed_block(&mut data, &mut dictionary, &block).unwrap();
+        blocks.push((block, start));
+    }
+
+    (blocks, data)
+}
+
+/// The serialised size of every block's trace, added up.
+fn trace_size(technique: &str, blocks: &[(Block, usize)], data: &[u8]) -> usize {
+    let mut tracer = Tracer::new(technique::lookup(technique).unwrap());
+    let mut size = 0;
+
+    for (i, (block, start)) in blocks.iter().enumerate() {
+        let end = blocks.get(i + 1).map_or(data.len(), |&(_, end)| end);
+        let (block_data, following) = (&data[*start..end], &data[end..]);
+
+        match *block {
+            Block::Uncompressed(_) => tracer.skip_block(block_data, following),
+            Block::FixedHuffman(ref codes) | Block::DynamicHuffman { ref codes, .. } => {
+                let trace = tracer.trace_block(block_data, following, codes);
+                size += serialise_trace::write(&trace).len();
+            }
+        }
+    }
+
+    size
+}
+
+fn read_manifest(text: &str) -> BTreeMap<(String, String), usize> {
+    text.lines()
+        .filter(|line| !line.starts_with('#') && !line.trim().is_empty())
+        .map(|line| {
+            let parts: Vec<&str> = line.split_whitespace().collect();
+            assert_eq!(3, parts.len(), "file technique size: {:?}", line);
+            (
+                (parts[0].to_string(), parts[1].to_string()),
+                parts[2].parse().unwrap(),
+            )
+        })
+        .collect()
+}
+
+fn write_manifest(sizes: &BTreeMap<(String, String), usize>) -> String {
+    let width = sizes.keys().map(|(file, _)| file.len()).max().unwrap_or(0);
+
+    let mut out = String::new();
+    writeln!(out, "# serialised trace bytes; see tests/golden.rs").unwrap();
+    for ((file, technique), size) in sizes {
+        writeln!(
+            out,
+            "{:width$} {:6} {:>8}",
+            file,
+            technique,
+            size,
+            width = width
+        )
+        .unwrap();
+    }
+    out
+}
+
+#[test]
+fn golden_sizes() {
+    let techniques = technique::names();
+
+    let mut sizes = BTreeMap::new();
+    for (name, file) in corpus() {
+        let (blocks, data) = decode(&file);
+        for technique in &techniques {
+            let size = trace_size(technique, &blocks, &data);
+            sizes.insert((name.clone(), technique.clone()), size);
+        }
+    }
+
+    if env::var_os(UPDATE_VAR).is_some() {
+        fs::write(manifest_path(), write_manifest(&sizes)).unwrap();
+        return;
+    }
+
+    let expected = read_manifest(&fs::read_to_string(manifest_path()).unwrap());
+
+    let mut regressions = Vec::new();
+    let (mut before, mut after) = (0, 0);
+
+    for (key, &size) in &sizes {
+        let recorded = match expected.get(key) {
+            Some(&recorded) => recorded,
+            None => {
+                regressions.push(format!("{} {}: not recorded", key.0, key.1));
+                continue;
+            }
+        };
+
+        before += recorded;
+        after += size;
+
+        let allowed = recorded + recorded * TOLERANCE_PERCENT / 100 + TOLERANCE_BYTES;
+        if size > allowed {
+            regressions.push(format!("{} {}: {} -> {}", key.0, key.1, recorded, size));
+        } else if size != recorded {
+            println!("{} {}: {} -> {}", key.0, key.1, recorded, size);
+        }
+    }
+
+    // a removed, or renamed, file or technique must be dropped from the manifest on purpose
+    for key in expected.keys() {
+        if !sizes.contains_key(key) {
+            regressions.push(format!("{} {}: recorded, but not traced", key.0, key.1));
+        }
+    }
+
+    println!("total: {} -> {}", before, after);
+
+    assert!(
+        regressions.is_empty(),
+        "traces got bigger, or the manifest is out of date; if that's expected, rerun with {}=1:\n{}",
+        UPDATE_VAR,
+        regressions.join("\n")
+    );
+}
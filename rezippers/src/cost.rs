@@ -0,0 +1,114 @@
+use std::io::Read;
+
+use anyhow::anyhow;
+use anyhow::Error;
+
+use librezip::cost;
+use librezip::cost::BlockCost;
+use librezip::technique;
+use librezip::Block;
+use librezip::CircularBuffer;
+use librezip::Tracer;
+
+pub fn run<R: Read>(mut reader: R, name: &str, show_decisions: bool) -> Result<(), Error> {
+    librezip::gzip::discard_header(&mut reader)?;
+
+    let constructor = technique::lookup(name).ok_or_else(|| {
+        anyhow!(
+            "unknown technique {:?}; try one of: {}",
+            name,
+            technique::names().join(", ")
+        )
+    })?;
+    let mut dictionary = CircularBuffer::new();
+    let mut data = Vec::new();
+    let mut blocks = Vec::new();
+
+    for block in librezip::parse_deflate(&mut reader) {
+        let block = block?;
+        let start = data.len();
+        librezip::decompressed_block(&mut data, &mut dictionary, &block)?;
+        blocks.push((block, start..data.len()));
+    }
+
+    // predicted like `trace` and `pack` would, carrying the technique across blocks
+    let mut tracer = Tracer::new(constructor);
+    let mut actual = 0;
+    let mut predicted = 0;
+
+    for (id, (block, range)) in blocks.iter().enumerate() {
+        let kind = match *block {
+            Block::Uncompressed(_) => "uncompressed",
+            Block::FixedHuffman(_) => "fixed huffman",
+            Block::DynamicHuffman { .. } => "dynamic huffman",
+        };
+
+        let cost = cost::block_cost(&mut tracer, &data[range.clone()], &data[range.end..], block)?;
+        println!("block {}: {}, {} byte(s):", id, kind, cost.len);
+        print(&cost, show_decisions);
+
+        actual += cost.actual;
+        predicted += cost.predicted;
+    }
+
+    println!(
+        "total: {} bit(s) actual, {} bit(s) predicted with {}",
+        actual, predicted, name
+    );
+
+    Ok(())
+}
+
+fn print(cost: &BlockCost, show_decisions: bool) {
+    let maybe = |bits: Option<u64>| bits.map_or_else(|| "-".to_string(), |b| b.to_string());
+
+    println!(" - actual: {} bit(s)", cost.actual);
+    println!(
+        " - predicted: {} bit(s) under the block's trees, {} bit(s) with its own",
+        maybe(cost.predicted_same_trees),
+        cost.predicted
+    );
+    println!(
+        " - alternatives: {} bit(s) fixed, {} bit(s) stored",
+        maybe(cost.fixed),
+        cost.stored
+    );
+
+    if cost.decisions.is_empty() {
+        return;
+    }
+
+    let cheaper = cost
+        .decisions
+        .iter()
+        .filter(|d| Some(true) == d.cheaper())
+        .count();
+    let dearer = cost
+        .decisions
+        .iter()
+        .filter(|d| Some(false) == d.cheaper())
+        .count();
+
+    println!(
+        " - {} decision(s) differed from our guess: {} cheaper per byte, {} not, {} unpriced",
+        cost.decisions.len(),
+        cheaper,
+        dearer,
+        cost.decisions.len() - cheaper - dearer
+    );
+
+    if !show_decisions {
+        return;
+    }
+
+    for decision in &cost.decisions {
+        println!(
+            "   {:6}. actual: {:?} ({} bit(s)), guess: {:?} ({} bit(s))",
+            decision.pos,
+            decision.actual,
+            maybe(decision.actual_bits.map(u64::from)),
+            decision.guess,
+            maybe(decision.guess_bits.map(u64::from)),
+        );
+    }
+}
@@ -1,51 +1,421 @@
+use std::collections::VecDeque;
+use std::io;
 use std::io::Read;
+use std::io::Write;
 
+use anyhow::anyhow;
 use anyhow::Error;
+use clap::ValueEnum;
+use serde::Serialize;
 
-use librezip;
+use librezip::gzip;
+use librezip::serialise_trace;
+use librezip::technique;
+use librezip::tracer::LOOKAHEAD;
 use librezip::Block;
+use librezip::CircularBuffer;
 use librezip::Code;
+use librezip::Trace;
+use librezip::Tracer;
 
-pub fn run<R: Read>(mut reader: R) -> Result<(), Error> {
-    librezip::gzip::discard_header(&mut reader)?;
-    for (id, block) in librezip::parse_deflate(&mut reader).into_iter().enumerate() {
-        let block = block?;
+/// Bump this when changing the meaning of, or removing, any field in the json output.
+const SCHEMA_VERSION: u32 = 2;
 
-        println!("block {}:", id);
-        use self::Block::*;
-        match block {
-            Uncompressed(data) => {
-                println!(" - uncompressed: {} bytes", data.len());
-            }
-            FixedHuffman(codes) => {
-                println!(" - fixed huffman:");
-                print(&codes);
+#[derive(Copy, Clone, Debug, Eq, PartialEq, ValueEnum)]
+pub enum Format {
+    Text,
+    /// A single document, containing the header and every block.
+    Json,
+    /// One `header` record, then one `block` record per block, then one `trailer` record.
+    Jsonl,
+}
+
+#[derive(Serialize)]
+struct JsonDocument {
+    schema: u32,
+    header: JsonHeader,
+    blocks: Vec<JsonBlock>,
+    trailer: JsonTrailer,
+}
+
+#[derive(Serialize)]
+#[serde(tag = "record", rename_all = "snake_case")]
+enum JsonRecord {
+    Header {
+        schema: u32,
+        #[serde(flatten)]
+        header: JsonHeader,
+    },
+    Block(JsonBlock),
+    Trailer(JsonTrailer),
+}
+
+#[derive(Clone, Serialize)]
+struct JsonHeader {
+    text: bool,
+    mtime: u32,
+    extra_flags: u8,
+    os: u8,
+    extra: Option<Vec<u8>>,
+    /// Lossily converted to utf-8.
+    name: Option<String>,
+    /// Lossily converted to utf-8.
+    comment: Option<String>,
+    header_crc: Option<u16>,
+}
+
+#[derive(Serialize)]
+struct JsonBlock {
+    index: usize,
+    #[serde(rename = "final")]
+    last: bool,
+    #[serde(rename = "type")]
+    kind: &'static str,
+    decompressed_len: usize,
+    /// Only for dynamic blocks: the raw bits of the tree definitions, as a string of '0' and '1'.
+    trees: Option<String>,
+    /// Absent for uncompressed blocks.
+    codes: Option<Vec<JsonCode>>,
+    trace: Option<JsonTraced>,
+}
+
+#[derive(Serialize)]
+#[serde(tag = "kind", rename_all = "snake_case")]
+enum JsonCode {
+    Literal { byte: u8 },
+    Reference { dist: u16, run: u16 },
+}
+
+#[derive(Serialize)]
+struct JsonTraced {
+    /// The registered name of the technique.
+    technique: String,
+    serialised_len: usize,
+    trace: Vec<JsonTrace>,
+}
+
+#[derive(Serialize)]
+#[serde(tag = "kind", rename_all = "snake_case")]
+enum JsonTrace {
+    Correct,
+    ActuallyLiteral,
+    Actually { dist: u16, run: u16 },
+}
+
+#[derive(Serialize)]
+struct JsonTrailer {
+    /// None if the file was truncated.
+    crc32: Option<u32>,
+    isize: Option<u32>,
+    trailing_bytes: usize,
+}
+
+/// A block, waiting for enough of the data after it to be traced.
+struct Pending {
+    id: usize,
+    last: bool,
+    block: Block,
+    data: Vec<u8>,
+}
+
+/// `technique` is the registered name of the technique to trace each block against, if any.
+pub fn run<R: Read>(mut reader: R, format: Format, technique: Option<&str>) -> Result<(), Error> {
+    let (header, _) = gzip::read_header(&mut reader)?;
+
+    let mut tracer = match technique {
+        Some(name) => Some(Tracer::new(technique::lookup(name).ok_or_else(|| {
+            anyhow!(
+                "unknown technique {:?}; try one of: {}",
+                name,
+                technique::names().join(", ")
+            )
+        })?)),
+        None => None,
+    };
+
+    let stdout = io::stdout();
+    let mut stdout = stdout.lock();
+
+    let header = json_header(header);
+
+    if Format::Jsonl == format {
+        serde_json::to_writer(
+            &mut stdout,
+            &JsonRecord::Header {
+                schema: SCHEMA_VERSION,
+                header: header.clone(),
+            },
+        )?;
+        writeln!(stdout)?;
+    }
+
+    let mut dictionary = CircularBuffer::new();
+    let mut json_blocks = Vec::new();
+    let mut it = librezip::parse_deflate(&mut reader).peekable();
+    let mut id = 0;
+
+    // blocks are held back until the tracer can see the data after them
+    let mut pending = VecDeque::new();
+
+    loop {
+        let done = match it.next() {
+            Some(block) => {
+                let block = block?;
+                let last = it.peek().is_none();
+                let mut data = Vec::new();
+                librezip::decompressed_block(&mut data, &mut dictionary, &block)?;
+                pending.push_back(Pending {
+                    id,
+                    last,
+                    block,
+                    data,
+                });
+                id += 1;
+                false
             }
-            DynamicHuffman { trees, codes } => {
-                println!(" - dynamic huffman: {:?}", trees);
-                print(&codes);
+            None => true,
+        };
+
+        while let Some((following, ready)) = next_ready(&mut pending, tracer.is_some(), done) {
+            let Pending {
+                id,
+                last,
+                block,
+                data,
+            } = ready;
+
+            let traced = tracer.as_mut().and_then(|tracer| match block {
+                Block::Uncompressed(_) => {
+                    tracer.skip_block(&data, &following);
+                    None
+                }
+                Block::FixedHuffman(ref codes) | Block::DynamicHuffman { ref codes, .. } => {
+                    Some(tracer.trace_block(&data, &following, codes))
+                }
+            });
+            let traced = technique.zip(traced);
+
+            match format {
+                Format::Text => print(&mut stdout, id, &block, traced)?,
+                Format::Json => json_blocks.push(json_block(id, last, &block, &data, traced)),
+                Format::Jsonl => {
+                    serde_json::to_writer(
+                        &mut stdout,
+                        &JsonRecord::Block(json_block(id, last, &block, &data, traced)),
+                    )?;
+                    writeln!(stdout)?;
+                }
             }
         }
+
+        if done {
+            break;
+        }
+    }
+
+    let mut trailer = Vec::new();
+    reader.read_to_end(&mut trailer)?;
+    let json_trailer = json_trailer(&trailer);
+
+    match format {
+        Format::Text => {}
+        Format::Json => {
+            serde_json::to_writer_pretty(
+                &mut stdout,
+                &JsonDocument {
+                    schema: SCHEMA_VERSION,
+                    header,
+                    blocks: json_blocks,
+                    trailer: json_trailer,
+                },
+            )?;
+            writeln!(stdout)?;
+        }
+        Format::Jsonl => {
+            serde_json::to_writer(&mut stdout, &JsonRecord::Trailer(json_trailer))?;
+            writeln!(stdout)?;
+        }
     }
 
     Ok(())
 }
 
-fn print(codes: &[Code]) {
+/// The first pending block, and the data after it, if there's enough of that to trace it.
+fn next_ready(
+    pending: &mut VecDeque<Pending>,
+    tracing: bool,
+    done: bool,
+) -> Option<(Vec<u8>, Pending)> {
+    if pending.is_empty() {
+        return None;
+    }
+
+    if !tracing {
+        return Some((Vec::new(), pending.pop_front()?));
+    }
+
+    let following: Vec<u8> = pending
+        .iter()
+        .skip(1)
+        .flat_map(|pending| pending.data.iter().copied())
+        .take(LOOKAHEAD)
+        .collect();
+
+    if following.len() < LOOKAHEAD && !done {
+        return None;
+    }
+
+    Some((following, pending.pop_front()?))
+}
+
+fn print<W: Write>(
+    mut into: W,
+    id: usize,
+    block: &Block,
+    traced: Option<(&str, Vec<Trace>)>,
+) -> Result<(), Error> {
+    writeln!(into, "block {}:", id)?;
+    use self::Block::*;
+    match *block {
+        Uncompressed(ref data) => {
+            writeln!(into, " - uncompressed: {} bytes", data.len())?;
+        }
+        FixedHuffman(ref codes) => {
+            writeln!(into, " - fixed huffman:")?;
+            print_codes(&mut into, codes)?;
+        }
+        DynamicHuffman {
+            ref trees,
+            ref codes,
+        } => {
+            writeln!(into, " - dynamic huffman: {:?}", trees)?;
+            print_codes(&mut into, codes)?;
+        }
+    }
+
+    if let Some((technique, trace)) = traced {
+        writeln!(
+            into,
+            " - trace: {}: {} byte(s): {}",
+            technique,
+            serialise_trace::write(&trace).len(),
+            trace.iter().map(|t| format!("{:?}", t)).collect::<String>()
+        )?;
+    }
+
+    Ok(())
+}
+
+fn print_codes<W: Write>(mut into: W, codes: &[Code]) -> Result<(), Error> {
     use self::Code::*;
 
     for code in codes {
         match *code {
             Literal(chr) => {
-                println!("    - lit: 0x{:02x}: {:?}", chr, char::from(chr));
+                writeln!(into, "    - lit: 0x{:02x}: {:?}", chr, char::from(chr))?;
             }
             Reference(r) => {
-                println!(
+                writeln!(
+                    into,
                     "    - backref: {} byte(s) back, {} bytes long",
                     r.dist,
                     r.run()
-                );
+                )?;
             }
         }
     }
+
+    Ok(())
+}
+
+fn json_header(header: gzip::Header) -> JsonHeader {
+    let lossy = |bytes: Option<Vec<u8>>| bytes.map(|b| String::from_utf8_lossy(&b).into_owned());
+    JsonHeader {
+        text: header.text,
+        mtime: header.mtime,
+        extra_flags: header.extra_flags,
+        os: header.os,
+        extra: header.extra,
+        name: lossy(header.name),
+        comment: lossy(header.comment),
+        header_crc: header.header_crc,
+    }
+}
+
+fn json_block(
+    index: usize,
+    last: bool,
+    block: &Block,
+    data: &[u8],
+    traced: Option<(&str, Vec<Trace>)>,
+) -> JsonBlock {
+    let (kind, trees, codes): (_, _, &[Code]) = match *block {
+        Block::Uncompressed(_) => ("uncompressed", None, &[]),
+        Block::FixedHuffman(ref codes) => ("fixed_huffman", None, codes),
+        Block::DynamicHuffman {
+            ref trees,
+            ref codes,
+        } => (
+            "dynamic_huffman",
+            Some(
+                trees
+                    .iter()
+                    .map(|bit| if bit { '1' } else { '0' })
+                    .collect(),
+            ),
+            codes,
+        ),
+    };
+
+    JsonBlock {
+        index,
+        last,
+        kind,
+        decompressed_len: data.len(),
+        trees,
+        codes: match *block {
+            Block::Uncompressed(_) => None,
+            _ => Some(codes.iter().map(|&c| json_code(c)).collect()),
+        },
+        trace: traced.map(|(technique, trace)| JsonTraced {
+            technique: technique.to_string(),
+            serialised_len: serialise_trace::write(&trace).len(),
+            trace: trace.into_iter().map(json_trace).collect(),
+        }),
+    }
+}
+
+fn json_code(code: Code) -> JsonCode {
+    match code {
+        Code::Literal(byte) => JsonCode::Literal { byte },
+        Code::Reference(r) => JsonCode::Reference {
+            dist: r.dist,
+            run: r.run(),
+        },
+    }
+}
+
+fn json_trace(trace: Trace) -> JsonTrace {
+    match trace {
+        Trace::Correct => JsonTrace::Correct,
+        Trace::ActuallyLiteral => JsonTrace::ActuallyLiteral,
+        Trace::Actually(r) => JsonTrace::Actually {
+            dist: r.dist,
+            run: r.run(),
+        },
+    }
+}
+
+fn json_trailer(trailer: &[u8]) -> JsonTrailer {
+    let word = |at: usize| {
+        trailer
+            .get(at..at + 4)
+            .map(|w| u32::from_le_bytes([w[0], w[1], w[2], w[3]]))
+    };
+
+    JsonTrailer {
+        crc32: word(0),
+        isize: word(4),
+        trailing_bytes: trailer.len().saturating_sub(8),
+    }
 }
@@ -6,7 +6,14 @@ extern crate flate2;
 extern crate librezip;
 
 mod cat;
+mod cost;
 mod dump;
+mod pristine;
+mod salvage;
+mod scan;
+mod tarball;
+mod trace;
+mod verify;
 mod zero;
 
 use std::fs;
@@ -26,9 +33,93 @@ struct Cli {
 
 #[derive(Subcommand)]
 enum Command {
-    Cat { file: Option<PathBuf> },
-    Dump { file: Option<PathBuf> },
-    Zero { file: Option<PathBuf> },
+    Cat {
+        file: Option<PathBuf>,
+    },
+    /// Compare the bit cost of each block with what our predictions, and the alternatives, would cost
+    Cost {
+        /// The registered technique to predict with; overrides --level
+        #[arg(long)]
+        technique: Option<String>,
+        /// The gzip level to predict with
+        #[arg(long, default_value_t = 6, value_parser = clap::value_parser!(u8).range(1..=9))]
+        level: u8,
+        /// List every point at which the encoder didn't do what we predicted
+        #[arg(long)]
+        decisions: bool,
+        file: Option<PathBuf>,
+    },
+    /// Print the header, blocks and codes of a gzip file
+    Dump {
+        #[arg(long, value_enum, default_value_t = dump::Format::Text)]
+        format: dump::Format,
+        /// Also trace each block against this registered technique, and include the trace
+        #[arg(long)]
+        technique: Option<String>,
+        /// Also trace each block against gzip at this level; a shorthand for --technique gzip-N
+        #[arg(long, value_parser = clap::value_parser!(u8).range(1..=9))]
+        trace_level: Option<u8>,
+        file: Option<PathBuf>,
+    },
+    /// Read, write and import pristine-tar style deltas for .tar.gz files
+    PristineGz {
+        #[command(subcommand)]
+        action: pristine::Action,
+    },
+    /// Decode as much of a damaged gzip file as possible, reporting on the damage
+    Salvage {
+        /// Scan past damage for the next plausible block, and continue from there
+        #[arg(long)]
+        resync: bool,
+        file: Option<PathBuf>,
+    },
+    /// Check that a gzip, zlib or zip file can be rebuilt bit-exactly, and at what cost
+    Verify {
+        /// The number of blocks to trace at once; defaults to the number of CPUs
+        #[arg(long)]
+        threads: Option<usize>,
+        file: Option<PathBuf>,
+    },
+    /// Recursively find compressed files, and report how well each can be traced, as JSON
+    Scan {
+        /// The number of files to process at once; defaults to the number of CPUs
+        #[arg(long)]
+        threads: Option<usize>,
+        dir: PathBuf,
+    },
+    /// Record how to rebuild a .tar.gz from the directory it's extracted into
+    TarPack {
+        tarball: PathBuf,
+        metadata: PathBuf,
+    },
+    /// Rebuild a .tar.gz from its metadata, and the directory it was extracted into
+    TarUnpack {
+        metadata: PathBuf,
+        dir: PathBuf,
+        tarball: PathBuf,
+    },
+    /// Trace each block against a technique, showing where its guesses were wrong
+    Trace {
+        /// The registered technique to trace with, e.g. `spicy`; overrides --level
+        #[arg(long)]
+        technique: Option<String>,
+        /// The gzip level to emulate
+        #[arg(long, default_value_t = 6, value_parser = clap::value_parser!(u8).range(1..=9))]
+        level: u8,
+        /// Only trace this block; may be repeated
+        #[arg(long = "block")]
+        blocks: Vec<usize>,
+        /// The number of bytes to show on each side of a miss
+        #[arg(long, default_value_t = 5)]
+        context: usize,
+        /// Only print the summary for each block
+        #[arg(long, short)]
+        quiet: bool,
+        file: Option<PathBuf>,
+    },
+    Zero {
+        file: Option<PathBuf>,
+    },
 }
 
 fn main() -> Result<(), Error> {
@@ -36,11 +127,62 @@ fn main() -> Result<(), Error> {
 
     match cli.command {
         Command::Cat { file } => cat::run(open_file(file)?),
-        Command::Dump { file } => dump::run(open_file(file)?),
+        Command::Cost {
+            technique,
+            level,
+            decisions,
+            file,
+        } => cost::run(
+            open_file(file)?,
+            &technique_name(technique, level),
+            decisions,
+        ),
+        Command::Dump {
+            format,
+            technique,
+            trace_level,
+            file,
+        } => dump::run(
+            open_file(file)?,
+            format,
+            technique
+                .or_else(|| trace_level.map(|level| technique_name(None, level)))
+                .as_deref(),
+        ),
+        Command::PristineGz { action } => pristine::run(action),
+        Command::Salvage { resync, file } => salvage::run(open_file(file)?, resync),
+        Command::Scan { threads, dir } => scan::run(&dir, threads),
+        Command::TarPack { tarball, metadata } => tarball::pack(&tarball, &metadata),
+        Command::TarUnpack {
+            metadata,
+            dir,
+            tarball,
+        } => tarball::unpack(&metadata, &dir, &tarball),
+        Command::Trace {
+            technique,
+            level,
+            blocks,
+            context,
+            quiet,
+            file,
+        } => trace::run(
+            open_file(file)?,
+            &trace::Options {
+                technique: technique_name(technique, level),
+                blocks,
+                context,
+                quiet,
+            },
+        ),
+        Command::Verify { threads, file } => verify::run(open_file(file)?, threads),
         Command::Zero { file } => zero::run(open_file(file)?),
     }
 }
 
+fn technique_name(technique: Option<String>, level: u8) -> String {
+    technique.unwrap_or_else(|| format!("gzip-{}", level))
+}
+
 fn open_file(file: Option<PathBuf>) -> Result<impl Read, Error> {
     Ok(match file {
         Some(path) => Box::new(io::BufReader::new(fs::File::open(path)?)),
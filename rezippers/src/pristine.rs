@@ -0,0 +1,86 @@
+use std::fs;
+use std::path::Path;
+use std::path::PathBuf;
+
+use anyhow::anyhow;
+use anyhow::Context;
+use anyhow::Error;
+use clap::Subcommand;
+use librezip::pristine;
+use librezip::pristine::GzDelta;
+
+/// Like `pristine-gz`'s commands, but the `.tar` is passed, rather than found by pristine-tar.
+#[derive(Subcommand)]
+pub enum Action {
+    /// Write a delta which rebuilds the .tar.gz from the .tar
+    Gendelta {
+        tarball: PathBuf,
+        tar: PathBuf,
+        delta: PathBuf,
+    },
+    /// Rebuild the .tar.gz from one of our deltas, and the .tar
+    Gengz {
+        delta: PathBuf,
+        tar: PathBuf,
+        tarball: PathBuf,
+    },
+    /// Replace one of pristine-tar's deltas with ours, given the .tar.gz it rebuilds
+    Import {
+        pristine: PathBuf,
+        tarball: PathBuf,
+        tar: PathBuf,
+        delta: PathBuf,
+    },
+    /// Print the fields of a delta, ours or pristine-tar's
+    Show { delta: PathBuf },
+}
+
+pub fn run(action: Action) -> Result<(), Error> {
+    match action {
+        Action::Gendelta {
+            tarball,
+            tar,
+            delta,
+        } => {
+            let delta_file = pristine::gendelta(&read(&tarball)?, &read(&tar)?)?.write()?;
+            fs::write(delta, delta_file)?;
+        }
+        Action::Gengz {
+            delta,
+            tar,
+            tarball,
+        } => {
+            let delta = GzDelta::read(&read(&delta)?)?;
+            fs::write(tarball, pristine::gengz(&delta, &read(&tar)?)?)?;
+        }
+        Action::Import {
+            pristine,
+            tarball,
+            tar,
+            delta,
+        } => {
+            let theirs = GzDelta::read(&read(&pristine)?)?;
+            let ours = pristine::import(&theirs, &read(&tarball)?, &read(&tar)?)?;
+            fs::write(delta, ours.write()?)?;
+        }
+        Action::Show { delta } => {
+            let delta = GzDelta::read(&read(&delta)?)?;
+            println!("version: {}", delta.version);
+            println!("params: {}", delta.params);
+            println!("filename: {}", delta.filename);
+            println!("timestamp: {}", delta.timestamp);
+            if let Some(ref xdelta) = delta.delta {
+                println!("delta: {} byte(s)", xdelta.len());
+            }
+            if let Some(ref rezip) = delta.rezip {
+                println!("rezip: {} byte(s)", rezip.len());
+            }
+        }
+    }
+
+    Ok(())
+}
+
+fn read(path: &Path) -> Result<Vec<u8>, Error> {
+    fs::read(path).with_context(|| anyhow!("reading {:?}", path))
+}
@@ -0,0 +1,48 @@
+use std::io;
+use std::io::Read;
+
+use anyhow::ensure;
+use anyhow::Error;
+
+pub fn run<R: Read>(mut reader: R, resync: bool) -> Result<(), Error> {
+    let header = librezip::gzip::discard_header(&mut reader)?;
+
+    let mut data = Vec::new();
+    reader.read_to_end(&mut data)?;
+
+    let stdout = io::stdout();
+    let report = librezip::recover::salvage(&data, stdout.lock(), resync)?;
+
+    let header_bits = header.len() as u64 * 8;
+
+    eprintln!("recovered: {} byte(s)", report.recovered_bytes);
+    eprintln!("unknown: {} byte(s)", report.unknown_bytes);
+    eprintln!("complete blocks: {}", report.complete_blocks);
+    for damage in &report.damage {
+        eprintln!(
+            "damage: block at bit {} failed at bit {}: {}",
+            header_bits + damage.block_start,
+            header_bits + damage.failed_at,
+            damage.error
+        );
+        if let Some(resumed_at) = damage.resumed_at {
+            eprintln!(" - resumed at bit {}", header_bits + resumed_at);
+        }
+    }
+
+    match report.end_bit {
+        Some(end_bit) => {
+            let trailing = data.len() as u64 - end_bit.div_ceil(8);
+            eprintln!("finished: {} trailing byte(s)", trailing);
+        }
+        None => eprintln!("not finished: the stream is incomplete"),
+    }
+
+    // everything recoverable is already out, but scripts need to tell this wasn't all of it
+    ensure!(
+        report.damage.is_empty() && report.end_bit.is_some(),
+        "the stream is damaged or incomplete"
+    );
+
+    Ok(())
+}
@@ -0,0 +1,231 @@
+use std::fs;
+use std::io;
+use std::io::Read;
+use std::io::Write;
+use std::path::Path;
+use std::path::PathBuf;
+
+use anyhow::Error;
+use librezip::pack;
+use librezip::pack::Container;
+use librezip::pack::Segment;
+use librezip::parse_deflate;
+use rayon::prelude::*;
+use serde::Serialize;
+
+/// Enough to recognise every container we support.
+const SNIFF_LEN: usize = 4;
+
+/// A zlib header is only two bytes, so plenty of plain files start with one.
+const ZLIB_HEADER_LEN: usize = 2;
+
+#[derive(Serialize)]
+struct Report {
+    files: Vec<FileReport>,
+    /// Files which looked like zlib, but which don't start with a valid block.
+    not_compressed: Vec<PathBuf>,
+    summary: Summary,
+}
+
+#[derive(Serialize)]
+struct FileReport {
+    path: PathBuf,
+    /// None if the file, or its directory, couldn't even be read to find out.
+    container: Option<&'static str>,
+    compressed_len: u64,
+    decompressed_len: Option<u64>,
+    metadata_len: Option<u64>,
+    /// metadata_len / compressed_len
+    ratio: Option<f64>,
+    /// The detected technique for each `DEFLATE` stream in the file.
+    techniques: Vec<String>,
+    failure: Option<String>,
+}
+
+#[derive(Default, Serialize)]
+struct Summary {
+    files: u64,
+    failures: u64,
+    not_compressed: u64,
+    compressed_len: u64,
+    metadata_len: u64,
+}
+
+pub fn run(root: &Path, threads: Option<usize>) -> Result<(), Error> {
+    // anything unreadable is reported, rather than stopping the scan of everything else
+    let mut candidates = Vec::new();
+    let mut files = Vec::new();
+    find_candidates(root, &mut candidates, &mut files);
+    candidates.sort_by(|(left, _), (right, _)| left.cmp(right));
+
+    let scanned: Vec<(&PathBuf, Option<FileReport>)> = pack::with_threads(threads, || {
+        Ok(candidates
+            .par_iter()
+            .map(|(path, container)| (path, scan(path, *container)))
+            .collect())
+    })?;
+
+    let mut not_compressed = Vec::new();
+    for (path, file) in scanned {
+        match file {
+            Some(file) => files.push(file),
+            None => not_compressed.push(path.clone()),
+        }
+    }
+    files.sort_by(|left, right| left.path.cmp(&right.path));
+
+    let mut summary = Summary {
+        not_compressed: not_compressed.len() as u64,
+        ..Summary::default()
+    };
+    for file in &files {
+        summary.files += 1;
+        if file.failure.is_some() {
+            summary.failures += 1;
+            continue;
+        }
+        summary.compressed_len += file.compressed_len;
+        summary.metadata_len += file.metadata_len.unwrap_or(0);
+    }
+
+    let stdout = io::stdout();
+    let mut stdout = stdout.lock();
+    serde_json::to_writer_pretty(
+        &mut stdout,
+        &Report {
+            files,
+            not_compressed,
+            summary,
+        },
+    )?;
+    writeln!(stdout)?;
+
+    Ok(())
+}
+
+fn find_candidates(
+    dir: &Path,
+    into: &mut Vec<(PathBuf, Container)>,
+    failures: &mut Vec<FileReport>,
+) {
+    let entries = match fs::read_dir(dir) {
+        Ok(entries) => entries,
+        Err(e) => return failures.push(FileReport::failed(dir, e.into())),
+    };
+
+    for entry in entries {
+        let entry = match entry {
+            Ok(entry) => entry,
+            Err(e) => {
+                failures.push(FileReport::failed(dir, e.into()));
+                continue;
+            }
+        };
+        let path = entry.path();
+        let file_type = match entry.file_type() {
+            Ok(file_type) => file_type,
+            Err(e) => {
+                failures.push(FileReport::failed(&path, e.into()));
+                continue;
+            }
+        };
+
+        if file_type.is_dir() {
+            find_candidates(&path, into, failures);
+        } else if file_type.is_file() {
+            match sniff(&path) {
+                Ok(Some(container)) => into.push((path, container)),
+                Ok(None) => (),
+                Err(e) => failures.push(FileReport::failed(&path, e)),
+            }
+        }
+    }
+}
+
+fn sniff(path: &Path) -> Result<Option<Container>, Error> {
+    let mut start = Vec::with_capacity(SNIFF_LEN);
+    fs::File::open(path)?
+        .take(SNIFF_LEN as u64)
+        .read_to_end(&mut start)?;
+    Ok(pack::container(&start))
+}
+
+impl FileReport {
+    fn new(path: &Path, container: Option<&'static str>) -> FileReport {
+        FileReport {
+            path: path.to_path_buf(),
+            container,
+            compressed_len: 0,
+            decompressed_len: None,
+            metadata_len: None,
+            ratio: None,
+            techniques: Vec::new(),
+            failure: None,
+        }
+    }
+
+    fn failed(path: &Path, e: Error) -> FileReport {
+        FileReport {
+            failure: Some(format!("{:#}", e)),
+            ..FileReport::new(path, None)
+        }
+    }
+}
+
+/// `None` if the file only looked compressed.
+fn scan(path: &Path, container: Container) -> Option<FileReport> {
+    let mut report = FileReport::new(
+        path,
+        Some(match container {
+            Container::Gzip => "gzip",
+            Container::Zip => "zip",
+            Container::Zlib => "zlib",
+        }),
+    );
+
+    let data = match fs::read(path) {
+        Ok(data) => data,
+        Err(e) => {
+            report.failure = Some(format!("{:#}", e));
+            return Some(report);
+        }
+    };
+
+    if Container::Zlib == container && !data.get(ZLIB_HEADER_LEN..).is_some_and(first_block_parses)
+    {
+        return None;
+    }
+
+    if let Err(e) = scan_into(&data, &mut report) {
+        report.failure = Some(format!("{:#}", e));
+    }
+
+    Some(report)
+}
+
+fn first_block_parses(data: &[u8]) -> bool {
+    matches!(parse_deflate(data).next(), Some(Ok(_)))
+}
+
+fn scan_into(data: &[u8], report: &mut FileReport) -> Result<(), Error> {
+    report.compressed_len = data.len() as u64;
+
+    let (metadata, decompressed) = pack::pack(data)?;
+    report.decompressed_len = Some(decompressed.len() as u64);
+
+    report.techniques = metadata
+        .segments
+        .iter()
+        .filter_map(|segment| match *segment {
+            Segment::Deflate(ref stream) => Some(stream.technique.clone()),
+            Segment::Raw(_) => None,
+        })
+        .collect();
+
+    let mut serialised = Vec::new();
+    metadata.write(&mut serialised)?;
+    report.metadata_len = Some(serialised.len() as u64);
+    report.ratio = Some(serialised.len() as f64 / data.len().max(1) as f64);
+
+    Ok(())
+}
@@ -0,0 +1,46 @@
+use std::fs;
+use std::io;
+use std::path::Path;
+
+use anyhow::Error;
+use librezip::pack::Limits;
+use librezip::pack::Metadata;
+use librezip::tarball;
+use librezip::tarball::TarMetadata;
+
+/// The metadata file is the compression's metadata, then the tar's.
+pub fn pack(tarball: &Path, metadata: &Path) -> Result<(), Error> {
+    let data = fs::read(tarball)?;
+    let (compression, tar) = tarball::pack_compressed(&data)?;
+
+    let mut out = Vec::new();
+    compression.write(&mut out)?;
+    tar.write(&mut out)?;
+    fs::write(metadata, &out)?;
+
+    println!(
+        "{} compressed byte(s), {} metadata byte(s)",
+        data.len(),
+        out.len()
+    );
+    Ok(())
+}
+
+pub fn unpack(metadata: &Path, dir: &Path, tarball: &Path) -> Result<(), Error> {
+    let files_len = tarball::files_len(dir)?;
+    let metadata_len = fs::metadata(metadata)?.len();
+
+    // the tar is its files, and what the metadata stores verbatim
+    let mut from = io::BufReader::new(fs::File::open(metadata)?);
+    let compression = Metadata::read_with_limits(
+        &mut from,
+        Limits::unpacking(files_len.saturating_add(metadata_len)),
+    )?;
+    let tar = TarMetadata::read(&mut from, Limits::unpacking(files_len))?;
+
+    fs::write(
+        tarball,
+        tarball::unpack_compressed(&compression, &tar, dir)?,
+    )?;
+    Ok(())
+}
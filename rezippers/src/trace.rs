@@ -0,0 +1,167 @@
+use std::io::Read;
+
+use anyhow::anyhow;
+use anyhow::ensure;
+use anyhow::Error;
+
+use librezip::serialise_trace;
+use librezip::technique;
+use librezip::Block;
+use librezip::CircularBuffer;
+use librezip::Code;
+use librezip::Trace;
+use librezip::Tracer;
+
+pub struct Options {
+    /// The registered name of the technique.
+    pub technique: String,
+    /// Only show these blocks; all blocks if empty.
+    pub blocks: Vec<usize>,
+    /// Bytes of data to show on each side of a miss.
+    pub context: usize,
+    /// Only print the summary for each block, not every miss.
+    pub quiet: bool,
+}
+
+#[derive(Default)]
+struct Summary {
+    hits: usize,
+    misses: usize,
+    serialised_len: usize,
+}
+
+pub fn run<R: Read>(mut reader: R, options: &Options) -> Result<(), Error> {
+    librezip::gzip::discard_header(&mut reader)?;
+
+    let name = &options.technique;
+    let constructor = technique::lookup(name).ok_or_else(|| {
+        anyhow!(
+            "unknown technique {:?}; try one of: {}",
+            name,
+            technique::names().join(", ")
+        )
+    })?;
+
+    let mut dictionary = CircularBuffer::new();
+    let mut data = Vec::new();
+    let mut blocks = Vec::new();
+
+    for block in librezip::parse_deflate(&mut reader) {
+        let block = block?;
+        let start = data.len();
+        librezip::decompressed_block(&mut data, &mut dictionary, &block)?;
+        blocks.push((block, start..data.len()));
+    }
+
+    for &id in &options.blocks {
+        ensure!(
+            id < blocks.len(),
+            "no block {}; the file has {}",
+            id,
+            blocks.len()
+        );
+    }
+
+    // every block is traced, even if it isn't shown, to keep the tracer's state right
+    let mut tracer = Tracer::new(constructor);
+    let mut total = Summary::default();
+
+    for (id, (block, range)) in blocks.iter().enumerate() {
+        let following = &data[range.end..];
+        let show = options.blocks.is_empty() || options.blocks.contains(&id);
+
+        let codes = match *block {
+            Block::Uncompressed(_) => {
+                tracer.skip_block(&data[range.clone()], following);
+                if show {
+                    println!(
+                        "block {}: uncompressed, {} byte(s): not traced",
+                        id,
+                        range.len()
+                    );
+                }
+                continue;
+            }
+            Block::FixedHuffman(ref codes) | Block::DynamicHuffman { ref codes, .. } => codes,
+        };
+
+        let mut guesses = Vec::new();
+        let trace = tracer.trace_block_with(&data[range.clone()], following, codes, |guess, _| {
+            guesses.push(guess.to_vec())
+        });
+
+        if !show {
+            continue;
+        }
+
+        let kind = match *block {
+            Block::FixedHuffman(_) => "fixed huffman",
+            _ => "dynamic huffman",
+        };
+        println!("block {}: {}, {} code(s):", id, kind, codes.len());
+
+        let summary = summarise(&trace, codes, &guesses, &data, range.start, options);
+        println!(
+            " - {}: {} hit(s), {} miss(es), {} byte(s) serialised",
+            name, summary.hits, summary.misses, summary.serialised_len
+        );
+
+        total.hits += summary.hits;
+        total.misses += summary.misses;
+        total.serialised_len += summary.serialised_len;
+    }
+
+    println!(
+        "total: {}: {} hit(s), {} miss(es), {} byte(s) serialised",
+        name, total.hits, total.misses, total.serialised_len
+    );
+
+    Ok(())
+}
+
+/// `start` is the position of the block in the `data`; `guesses` are what we guessed at each miss.
+fn summarise(
+    trace: &[Trace],
+    codes: &[Code],
+    guesses: &[Vec<Code>],
+    data: &[u8],
+    start: usize,
+    options: &Options,
+) -> Summary {
+    let mut summary = Summary::default();
+    let mut pos = start;
+    let mut guesses = guesses.iter();
+
+    for (t, c) in trace.iter().zip(codes) {
+        let actual = match *t {
+            Trace::Correct => {
+                summary.hits += 1;
+                pos += usize::from(c.emitted_bytes());
+                continue;
+            }
+            Trace::ActuallyLiteral => format!("literal {:?}", char::from(data[pos])),
+            Trace::Actually(r) => format!("{:?}", r),
+        };
+
+        summary.misses += 1;
+
+        if !options.quiet {
+            let context = String::from_utf8_lossy(
+                &data[pos.saturating_sub(options.context)..(pos + options.context).min(data.len())],
+            );
+            println!(
+                "   {:6}. {:?} guess: {:?} actual: {}",
+                pos - start,
+                context,
+                guesses.next().expect("one per miss"),
+                actual
+            );
+        }
+
+        pos += usize::from(c.emitted_bytes());
+    }
+
+    summary.serialised_len = serialise_trace::write(trace).len();
+
+    summary
+}
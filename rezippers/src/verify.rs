@@ -0,0 +1,41 @@
+use std::io::Read;
+
+use anyhow::bail;
+use anyhow::Error;
+use librezip::pack;
+use librezip::pack::Segment;
+
+pub fn run<R: Read>(mut reader: R, threads: Option<usize>) -> Result<(), Error> {
+    let mut data = Vec::new();
+    reader.read_to_end(&mut data)?;
+
+    let verification = pack::with_threads(threads, || pack::verify(&data))?;
+
+    if let Some(offset) = verification.mismatch {
+        bail!(
+            "rebuilt file differs from the original at byte offset {}",
+            offset
+        );
+    }
+
+    for (id, segment) in verification.metadata.segments.iter().enumerate() {
+        if let Segment::Deflate(ref stream) = *segment {
+            println!(
+                "stream {}: {}, {} block(s)",
+                id,
+                stream.technique,
+                stream.blocks.len()
+            );
+        }
+    }
+
+    println!(
+        "ok: {} compressed byte(s), {} decompressed byte(s), {} metadata byte(s) ({:.2}%)",
+        verification.compressed_len,
+        verification.decompressed_len,
+        verification.metadata_len,
+        100. * verification.metadata_len as f64 / verification.compressed_len.max(1) as f64,
+    );
+
+    Ok(())
+}